@@ -0,0 +1,33 @@
+/* vim: set sw=2 expandtab tw=80: */
+
+#include <stdio.h>
+#include "tock.h"
+#include "network.h"
+#include "config.h"
+
+// Gives the API token to the kernel, which adds it to the requests of all the apps to
+// API_ENDPOINT; the token is built into this app only, and the board lets no other app
+// replace it. Flash this app, reset the board with button A held (provisioning mode), then
+// remove the app.
+#ifndef API_TOKEN
+#define API_TOKEN ""
+#endif
+
+int main(void) {
+  if (!driver_exists(DRIVER_NUM_NETWORK)) {
+    printf("No network driver\n");
+    return 0;
+  }
+
+  int status = network_provision_credential(API_ENDPOINT, API_TOKEN);
+  if (status == TOCK_STATUSCODE_NOSUPPORT) {
+    printf("[ERR] The board keeps no credential or is not in provisioning mode (hold button A at reset)\n");
+  } else if (status != 0) {
+    printf("[ERR] Failed to provision the credential: %d\n", status);
+  } else if (API_TOKEN[0] == '\0') {
+    printf("API credential removed\n");
+  } else {
+    printf("API credential provisioned for %s\n", API_ENDPOINT);
+  }
+  return 0;
+}
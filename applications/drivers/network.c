@@ -5,19 +5,24 @@
 typedef struct response {
     bool done;
     int status;
+    int http_status;
+    int len;
 } response;
 
 static bool processing = false; // Network processing lock
 
-static void request_done(int status, int len, int unused, void* ud) {
+#define RESPONSE_BUFFER_SIZE 1024
+
+static void request_done(int status, int http_status, int len, void* ud) {
     response* res = (response*) ud;
     res->done = true;
     res->status = status;
+    res->http_status = http_status;
+    res->len = len;
 }
 
-char* _allow_buffers_access(const char* url) {
+char* _allow_buffers_access(const char* url, size_t res_buf_size) {
     // Allocate response buffer
-    const size_t res_buf_size = 1024;
     char* data_buffer = (char*) calloc(res_buf_size, sizeof(char));
     if (data_buffer == NULL) {
         printf("[ERR] Failed to allocate request response buffer\n");
@@ -39,37 +44,84 @@ char* _allow_buffers_access(const char* url) {
     return data_buffer;
 }
 
-char* network_get(const char *url, int *status) {
+// Sends the request with the driver command `command_num` (1, 4 or 5) and its arguments
+static char* _network_command(int command_num, int arg1, int arg2, const char *url, const char *headers,
+                              const char *payload, int *status, int *http_status, int *body_len) {
     if (processing) {
-        printf("[WARN] Still processing; skipping GET request\n");
+        printf("[WARN] Still processing; skipping request\n");
         return NULL;
     }
     processing = true;
 
     *status = 0;
-    response res = { false, 0 };
+    *http_status = 0;
+    if (body_len != NULL) {
+        *body_len = 0;
+    }
+    response res = { false, 0, 0, 0 };
+    size_t res_buf_size = RESPONSE_BUFFER_SIZE;
+    // Only the requests without side effects on the server can be sent twice
+    bool can_retry = command_num == 1 && (arg1 == NETWORK_GET || arg1 == NETWORK_HEAD);
 
-    char* data_buffer = _allow_buffers_access(url);
+retry:;
+    char* data_buffer = _allow_buffers_access(url, res_buf_size);
     if (!data_buffer) {
         goto end;
     }
 
+    if (headers != NULL) {
+        allow_ro_return_t ret_allow_headers = allow_readonly(DRIVER_NUM_NETWORK, 2, headers, strlen(headers));
+        if (ret_allow_headers.status != TOCK_STATUSCODE_SUCCESS) {
+            printf("[ERR] Failed allow_readonly on request headers\n");
+            goto cleanup;
+        }
+    }
+
+    if (payload != NULL) {
+        allow_ro_return_t ret_allow_payload = allow_readonly(DRIVER_NUM_NETWORK, 1, payload, strlen(payload));
+        if (ret_allow_payload.status != TOCK_STATUSCODE_SUCCESS) {
+            printf("[ERR] Failed allow_readonly on request body\n");
+            goto cleanup;
+        }
+    }
+
     subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 0, request_done, &res);
     if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS) {
         printf("[ERR] Failed to subscribe to network driver\n");
         goto cleanup;
     }
 
-    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 1, 0, 0);
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, command_num, arg1, arg2);
     if (sys.type == TOCK_SYSCALL_SUCCESS) {
         yield_for(&res.done);
         *status = res.status;
+        *http_status = res.http_status;
+        if (body_len != NULL) {
+            *body_len = res.len;
+        }
+        if (*status == NETWORK_ERROR_TRUNCATED && can_retry && res_buf_size == RESPONSE_BUFFER_SIZE) {
+            // The response was truncated; retry once with a buffer that fits all of it
+            printf("[WARN] Response truncated; retrying with a %d bytes buffer\n", res.len + 1);
+            free(data_buffer);
+            res_buf_size = res.len + 1;
+            res.done = false;
+            goto retry;
+        }
+        if (*status == NETWORK_ERROR_TRUNCATED && !can_retry) {
+            // Sending the request again could repeat its action on the server: the caller gets
+            // the truncated body, with the full length in `body_len`
+            printf("[WARN] Response truncated to %d of %d bytes\n", (int) res_buf_size - 1, res.len);
+            data_buffer[res_buf_size - 1] = '\0';
+            goto end;
+        }
         if (*status != 0) {
-            printf("[WARN] Network request error status\n");
+            printf("[WARN] Network request error status %#x\n", *status);
             goto cleanup;
         }
     } else {
-        printf("[ERR] Failed GET driver command\n");
+        *status = sys.data[0];
+        printf("[ERR] Failed request driver command\n");
+        goto cleanup;
     }
     goto end;
 
@@ -78,6 +130,8 @@ cleanup:
     data_buffer = NULL;
 
 end:
+    allow_readonly(DRIVER_NUM_NETWORK, 2, NULL, 0); // unallow headers
+    allow_readonly(DRIVER_NUM_NETWORK, 1, NULL, 0); // unallow payload
     allow_readonly(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow address
     allow_readwrite(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow buffer
 
@@ -85,45 +139,318 @@ end:
     return data_buffer;
 }
 
-void network_post(const char* url, const char* payload) {
+char* network_request(int method, const char *url, const char *headers, int content_type, const char *payload,
+                      int *status, int *http_status, int *body_len) {
+    return _network_command(1, method, content_type, url, headers, payload, status, http_status, body_len);
+}
+
+char* network_get(const char *url, int *status, int *http_status) {
+    return network_request(NETWORK_GET, url, NULL, NETWORK_JSON, NULL, status, http_status, NULL);
+}
+
+int network_post(const char* url, const char* payload) {
+    int status, http_status;
+    char* data_buffer = network_request(NETWORK_POST, url, NULL, NETWORK_JSON, payload, &status, &http_status, NULL);
+    if (data_buffer == NULL) {
+        return -1;
+    }
+
+    free(data_buffer);
+    return http_status;
+}
+
+int network_post_or_queue(const char* url, const char* payload, int tag) {
+    int status, http_status;
+    char* data_buffer = _network_command(4, NETWORK_JSON, tag, url, NULL, payload, &status, &http_status, NULL);
+    if (data_buffer == NULL) {
+        return status == NETWORK_ERROR_QUEUED ? 0 : -1;
+    }
+
+    free(data_buffer);
+    return http_status;
+}
+
+int network_queue_post(const char* url, const char* payload, int tag) {
+    int status, http_status;
+    char* data_buffer = _network_command(5, NETWORK_JSON, tag, url, NULL, payload, &status, &http_status, NULL);
+    free(data_buffer);
+    return status == NETWORK_ERROR_QUEUED ? 0 : -1;
+}
+
+int network_set_timeout(int timeout_ms) {
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 2, timeout_ms, 0);
+    return sys.type == TOCK_SYSCALL_SUCCESS ? 0 : -1;
+}
+
+int network_cancel(void) {
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 3, 0, 0);
+    return sys.type == TOCK_SYSCALL_SUCCESS ? 0 : -1;
+}
+
+int network_set_response_headers_buffer(char* buffer, size_t len) {
+    allow_rw_return_t ret_allow = allow_readwrite(DRIVER_NUM_NETWORK, 1, buffer, len);
+    return ret_allow.status == TOCK_STATUSCODE_SUCCESS ? 0 : -1;
+}
+
+static network_push_callback *push_callback = NULL;
+
+static void push_received(int status, int len, __attribute__ ((unused)) int unused, void* ud) {
+    if (push_callback != NULL) {
+        push_callback(status, len, ud);
+    }
+}
+
+int network_subscribe_push(char *buffer, size_t len, network_push_callback callback, void *ud) {
+    push_callback = callback;
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 1, buffer != NULL ? push_received : NULL, ud);
+    if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS) {
+        return -1;
+    }
+
+    allow_rw_return_t ret_allow = allow_readwrite(DRIVER_NUM_NETWORK, 2, buffer, buffer != NULL ? len : 0);
+    return ret_allow.status == TOCK_STATUSCODE_SUCCESS ? 0 : -1;
+}
+
+static network_flushed_callback *flushed_callback = NULL;
+
+static void request_flushed(int tag, int status, int http_status, void* ud) {
+    if (flushed_callback != NULL) {
+        flushed_callback(tag, status, http_status, ud);
+    }
+}
+
+int network_subscribe_flushed(network_flushed_callback callback, void *ud) {
+    flushed_callback = callback;
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 2, callback != NULL ? request_flushed : NULL, ud);
+    return ret_subscribe.status == TOCK_STATUSCODE_SUCCESS ? 0 : -1;
+}
+
+int network_link_state(int *state, int *proxy, int *max_request_len) {
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 6, 0, 0);
+    if (sys.type != TOCK_SYSCALL_SUCCESS_U32_U32_U32) {
+        return -1;
+    }
+
+    *state = sys.data[0];
+    *proxy = sys.data[1];
+    *max_request_len = sys.data[2];
+    return 0;
+}
+
+int network_handshake(void) {
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 7, 0, 0);
+    return sys.type == TOCK_SYSCALL_SUCCESS ? 0 : -1;
+}
+
+int network_subscribe_link(network_link_callback callback, void *ud) {
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 3, callback, ud);
+    return ret_subscribe.status == TOCK_STATUSCODE_SUCCESS ? 0 : -1;
+}
+
+static network_progress_callback *progress_callback = NULL;
+
+static void progress_received(int received, int total, __attribute__ ((unused)) int unused, void* ud) {
+    progress_callback(received, total, ud);
+}
+
+int network_set_progress(size_t interval, network_progress_callback callback, void *ud) {
+    progress_callback = callback;
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 4, callback != NULL ? progress_received : NULL, ud);
+    if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS) {
+        return -1;
+    }
+
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 8, callback != NULL ? interval : 0, 0);
+    return sys.type == TOCK_SYSCALL_SUCCESS ? 0 : -1;
+}
+
+typedef struct stream {
+    char *buffer;
+    network_chunk_callback *callback;
+    void *ud;
+} stream;
+
+static void chunk_received(int len, int more, __attribute__ ((unused)) int unused, void* ud) {
+    stream* str = (stream*) ud;
+    str->callback(str->buffer, len, more, str->ud);
+    if (more) {
+        command(DRIVER_NUM_NETWORK, 10, 0, 0); // acknowledge the chunk
+    }
+}
+
+int network_get_streamed(const char *url, char *buffer, size_t len, network_chunk_callback callback, void *ud,
+                         int *http_status) {
     if (processing) {
-        printf("[WARN] Still processing; skipping POST request\n");
-        return;
+        printf("[WARN] Still processing; skipping request\n");
+        return TOCK_STATUSCODE_BUSY;
     }
     processing = true;
 
-    response res = { false, 0 };
+    int status;
+    *http_status = 0;
+    response res = { false, 0, 0, 0 };
+    stream str = { buffer, callback, ud };
 
-    char* data_buffer = _allow_buffers_access(url);
-    if (!data_buffer) {
-        goto cleanup;
+    allow_rw_return_t ret_allow_buffer = allow_readwrite(DRIVER_NUM_NETWORK, 0, buffer, len);
+    allow_ro_return_t ret_allow = allow_readonly(DRIVER_NUM_NETWORK, 0, url, strlen(url));
+    if (ret_allow_buffer.status != TOCK_STATUSCODE_SUCCESS || ret_allow.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to allow the buffers of the streamed request\n");
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
     }
-    
-    allow_ro_return_t ret_allow_payload = allow_readonly(DRIVER_NUM_NETWORK, 1, payload, strlen(payload));
-    if (ret_allow_payload.status != TOCK_STATUSCODE_SUCCESS) {
-        printf("[ERR] Failed allow_readonly on POST body\n");
-        goto cleanup;
+
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 0, request_done, &res);
+    subscribe_return_t ret_subscribe_chunk = subscribe(DRIVER_NUM_NETWORK, 5, chunk_received, &str);
+    if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS || ret_subscribe_chunk.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to subscribe to network driver\n");
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
+    }
+
+    command(DRIVER_NUM_NETWORK, 9, 1, 0);
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 1, NETWORK_GET, NETWORK_JSON);
+    if (sys.type == TOCK_SYSCALL_SUCCESS) {
+        yield_for(&res.done);
+        status = res.status;
+        *http_status = res.http_status;
+        if (status != 0) {
+            printf("[WARN] Network request error status %#x\n", status);
+        }
+    } else {
+        status = sys.data[0];
+        printf("[ERR] Failed request driver command\n");
+    }
+    command(DRIVER_NUM_NETWORK, 9, 0, 0);
+
+end:
+    subscribe(DRIVER_NUM_NETWORK, 5, NULL, NULL);
+    allow_readonly(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow address
+    allow_readwrite(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow buffer
+
+    processing = false;
+    return status;
+}
+
+int network_json_get(const char *json, const char *path, char *value, size_t len, int *type) {
+    int value_len = -1;
+
+    allow_ro_return_t ret_allow_json = allow_readonly(DRIVER_NUM_NETWORK, 3, json, strlen(json));
+    allow_ro_return_t ret_allow_path = allow_readonly(DRIVER_NUM_NETWORK, 4, path, strlen(path));
+    allow_rw_return_t ret_allow_value = allow_readwrite(DRIVER_NUM_NETWORK, 3, value, len);
+    if (ret_allow_json.status != TOCK_STATUSCODE_SUCCESS || ret_allow_path.status != TOCK_STATUSCODE_SUCCESS ||
+        ret_allow_value.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to allow the buffers of the JSON field\n");
+        goto end;
+    }
+
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 11, 0, 0);
+    if (sys.type == TOCK_SYSCALL_SUCCESS_U32_U32) {
+        value_len = sys.data[0];
+        if (type != NULL) {
+            *type = sys.data[1];
+        }
+        // The driver leaves a value that fills the buffer unterminated
+        if (len > 0 && (size_t) value_len >= len) {
+            value[len - 1] = '\0';
+        }
+    }
+
+end:
+    allow_readonly(DRIVER_NUM_NETWORK, 3, NULL, 0); // unallow document
+    allow_readonly(DRIVER_NUM_NETWORK, 4, NULL, 0); // unallow path
+    allow_readwrite(DRIVER_NUM_NETWORK, 3, NULL, 0); // unallow value
+    return value_len;
+}
+
+int network_set_conditional(bool conditional) {
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 12, conditional, 0);
+    return sys.type == TOCK_SYSCALL_SUCCESS ? 0 : -1;
+}
+
+int network_get_cached(const char *url, char *buffer, size_t len, int *http_status) {
+    if (processing) {
+        printf("[WARN] Still processing; skipping request\n");
+        return TOCK_STATUSCODE_BUSY;
+    }
+    processing = true;
+
+    int status;
+    *http_status = 0;
+    response res = { false, 0, 0, 0 };
+
+    allow_rw_return_t ret_allow_buffer = allow_readwrite(DRIVER_NUM_NETWORK, 0, buffer, len);
+    allow_ro_return_t ret_allow = allow_readonly(DRIVER_NUM_NETWORK, 0, url, strlen(url));
+    if (ret_allow_buffer.status != TOCK_STATUSCODE_SUCCESS || ret_allow.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to allow the buffers of the request\n");
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
     }
 
     subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 0, request_done, &res);
     if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS) {
         printf("[ERR] Failed to subscribe to network driver\n");
-        goto cleanup;
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
     }
 
-    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 1, 0, 0);
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 1, NETWORK_GET, NETWORK_JSON);
     if (sys.type == TOCK_SYSCALL_SUCCESS) {
         yield_for(&res.done);
-        goto cleanup;
+        status = res.status;
+        *http_status = res.http_status;
+        if (status != 0 && status != NETWORK_ERROR_NOT_MODIFIED) {
+            printf("[WARN] Network request error status %#x\n", status);
+        }
     } else {
-        printf("[ERR] Failed POST driver command\n");
+        status = sys.data[0];
+        printf("[ERR] Failed request driver command\n");
     }
 
-cleanup:
-    allow_readonly(DRIVER_NUM_NETWORK, 1, NULL, 0); // unallow payload
+end:
     allow_readonly(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow address
-    allow_readwrite(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow response buffer
+    allow_readwrite(DRIVER_NUM_NETWORK, 0, NULL, 0); // unallow buffer
 
-    free(data_buffer);
     processing = false;
-}
\ No newline at end of file
+    return status;
+}
+
+static void credential_provisioned(int status, __attribute__ ((unused)) int unused1,
+                                   __attribute__ ((unused)) int unused2, void* ud) {
+    response* res = (response*) ud;
+    res->done = true;
+    res->status = status;
+}
+
+int network_provision_credential(const char *origin, const char *token) {
+    int status;
+    response res = { false, 0, 0, 0 };
+
+    allow_ro_return_t ret_allow_origin = allow_readonly(DRIVER_NUM_NETWORK, 5, origin, strlen(origin));
+    allow_ro_return_t ret_allow_token = allow_readonly(DRIVER_NUM_NETWORK, 6, token, strlen(token));
+    if (ret_allow_origin.status != TOCK_STATUSCODE_SUCCESS || ret_allow_token.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to allow the buffers of the credential\n");
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
+    }
+
+    subscribe_return_t ret_subscribe = subscribe(DRIVER_NUM_NETWORK, 6, credential_provisioned, &res);
+    if (ret_subscribe.status != TOCK_STATUSCODE_SUCCESS) {
+        printf("[ERR] Failed to subscribe to network driver\n");
+        status = TOCK_STATUSCODE_FAIL;
+        goto end;
+    }
+
+    syscall_return_t sys = command(DRIVER_NUM_NETWORK, 13, 0, 0);
+    if (sys.type == TOCK_SYSCALL_SUCCESS) {
+        yield_for(&res.done);
+        status = res.status;
+    } else {
+        status = sys.data[0];
+    }
+
+end:
+    subscribe(DRIVER_NUM_NETWORK, 6, NULL, NULL);
+    allow_readonly(DRIVER_NUM_NETWORK, 5, NULL, 0); // unallow origin
+    allow_readonly(DRIVER_NUM_NETWORK, 6, NULL, 0); // unallow token
+    return status;
+}
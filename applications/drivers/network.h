@@ -1,6 +1,149 @@
 #pragma once
 
+#include "tock.h"
+
 #define DRIVER_NUM_NETWORK 0xa0001
 
-char * network_get(const char *url, int *status);
-void network_post(const char *url, const char *payload);
\ No newline at end of file
+enum network_method {
+    NETWORK_GET,
+    NETWORK_POST,
+    NETWORK_PUT,
+    NETWORK_PATCH,
+    NETWORK_DELETE,
+    NETWORK_HEAD,
+};
+
+enum network_content_type {
+    NETWORK_JSON,
+    NETWORK_FORM,
+    NETWORK_TEXT,
+    NETWORK_OCTET_STREAM,
+    NETWORK_CUSTOM_CONTENT_TYPE, // Content-Type is given in `headers`
+};
+
+// Request failures reported in `status`, besides the TOCK_STATUSCODE_* codes
+enum network_error {
+    NETWORK_ERROR_PROXY_UNREACHABLE = 0x100,  // the proxy is not running or the link to it failed
+    NETWORK_ERROR_CONNECTION_REFUSED = 0x101, // the server refused the connection
+    NETWORK_ERROR_DNS_FAILURE = 0x102,        // the host of the URL could not be resolved
+    NETWORK_ERROR_MALFORMED_RESPONSE = 0x103, // not valid HTTP or corrupted on the serial link
+    NETWORK_ERROR_TIMEOUT = 0x104,            // the server did not answer in time
+    NETWORK_ERROR_TRUNCATED = 0x105,          // the body did not fit into the response buffer
+    NETWORK_ERROR_INVALID_REQUEST = 0x106,    // the proxy could not parse the request
+    NETWORK_ERROR_CONNECTION_FAILED = 0x107,  // other connection failures
+    NETWORK_ERROR_QUEUED = 0x108,             // the request was kept in the offline queue
+    NETWORK_ERROR_NOT_MODIFIED = 0x109,       // the response did not change, see `network_get_cached`
+};
+
+// Returns the response body; `status` is the driver status and `http_status` the response status code
+// (TOCK_STATUSCODE_OFF while no compatible proxy is connected).
+// `url` must be visible ASCII, with spaces and other bytes percent-encoded; the driver refuses the
+// request with TOCK_STATUSCODE_INVAL otherwise.
+// `headers` are extra request headers ("Name: value" lines separated by "\r\n"; NULL for none).
+// `payload` is the request body (NULL for none); only POST, PUT, PATCH and DELETE accept a body.
+// `body_len` (NULL to ignore it) is the full body length. A truncated GET or HEAD response is fetched
+// again with a buffer that fits it; for the other methods, which must not be sent twice, the truncated
+// body is returned with the NETWORK_ERROR_TRUNCATED status.
+char * network_request(int method, const char *url, const char *headers, int content_type, const char *payload,
+                       int *status, int *http_status, int *body_len);
+// Returns the response body; `status` is the driver status and `http_status` the response status code
+char * network_get(const char *url, int *status, int *http_status);
+// Returns the response status code or -1 if the request failed
+int network_post(const char *url, const char *payload);
+// Like `network_post`, but if the server cannot be reached the request is kept in the offline queue
+// and sent again later; returns the response status code, 0 if the request was queued or -1
+int network_post_or_queue(const char *url, const char *payload, int tag);
+// Keeps the request in the offline queue, to be sent once the server can be reached; returns 0 or -1
+int network_queue_post(const char *url, const char *payload, int tag);
+// Sets the time the proxy has to answer each request of this app (0 restores the default timeout)
+int network_set_timeout(int timeout_ms);
+// Cancels the ongoing or queued request of this app; the pending call returns with the CANCEL status
+int network_cancel(void);
+// Sets a buffer that receives the status line and the headers of the following responses (NULL to stop)
+int network_set_response_headers_buffer(char* buffer, size_t len);
+
+// Called for each message pushed by the server, with the driver status (0, NETWORK_ERROR_TRUNCATED
+// or NETWORK_ERROR_MALFORMED_RESPONSE) and the message length
+typedef void (network_push_callback)(int status, int len, void *ud);
+// Receives the messages pushed by the server into `buffer`, zero terminated (NULL to unsubscribe); each
+// message overwrites the previous one. A message of `len` bytes or more is truncated, without a
+// terminator, and reported with NETWORK_ERROR_TRUNCATED.
+int network_subscribe_push(char *buffer, size_t len, network_push_callback callback, void *ud);
+
+// Called when a request of the offline queue is sent, with the tag it was queued with, the driver
+// status and the response status code; a request that fails otherwise than by not reaching the
+// server is dropped from the queue and reported with its error
+typedef void (network_flushed_callback)(int tag, int status, int http_status, void *ud);
+// Reports the requests of the offline queue that are sent (NULL to unsubscribe)
+int network_subscribe_flushed(network_flushed_callback callback, void *ud);
+
+enum network_link_state {
+    NETWORK_LINK_DOWN,         // no proxy answered the handshake; requests fail with TOCK_STATUSCODE_OFF
+    NETWORK_LINK_HANDSHAKING,
+    NETWORK_LINK_UP,
+    NETWORK_LINK_INCOMPATIBLE, // the proxy speaks another protocol version
+};
+
+// Proxy capabilities, in the bits 8 and above of `proxy`
+#define NETWORK_PROXY_PUSH (1 << 8)
+#define NETWORK_PROXY_COMPRESSION (1 << 9)
+#define NETWORK_PROXY_PACING (1 << 10) // needed to stream responses
+
+// Gets the state of the link to the proxy; `proxy` is the protocol version of the proxy and its
+// capabilities (NETWORK_PROXY_*), `max_request_len` the longest request it accepts
+int network_link_state(int *state, int *proxy, int *max_request_len);
+// Sends the handshake to the proxy again; the callback of `network_subscribe_link` reports its result
+int network_handshake(void);
+// Called when the state of the link to the proxy changes
+typedef void (network_link_callback)(int state, int proxy, int max_request_len, void *ud);
+int network_subscribe_link(network_link_callback callback, void *ud);
+
+// Called while a response comes in, with the number of bytes received so far and the response length
+typedef void (network_progress_callback)(int received, int total, void *ud);
+// Reports the progress of the following responses each time `interval` more bytes arrived
+// (NULL callback to stop)
+int network_set_progress(size_t interval, network_progress_callback callback, void *ud);
+
+// Called for each chunk of a streamed response body, zero terminated if there is room; `more` is
+// false for the last chunk
+typedef void (network_chunk_callback)(char *chunk, int len, bool more, void *ud);
+// Sends a GET request and streams the response body through `buffer`: the callback gets each chunk
+// of up to `len` bytes, and the next chunk comes once it returns. Returns the driver status and sets
+// `http_status` to the response status code (TOCK_STATUSCODE_NOSUPPORT if the proxy cannot pace).
+int network_get_streamed(const char *url, char *buffer, size_t len, network_chunk_callback callback, void *ud,
+                         int *http_status);
+
+// Types of the values given by `network_json_get`
+enum network_json_type {
+    NETWORK_JSON_STRING,
+    NETWORK_JSON_NUMBER,
+    NETWORK_JSON_BOOL,
+    NETWORK_JSON_NULL,
+    NETWORK_JSON_OBJECT,
+    NETWORK_JSON_ARRAY,
+};
+
+// Writes the value at `path` (object keys and array indices, e.g. "items[0].name") in the JSON
+// document `json` into `value`, zero terminated and truncated to `len - 1` bytes: strings unescaped,
+// the other values as they are in the document. Returns the full value length and sets `type`
+// (NULL to ignore it), or returns -1 if the document has no such value or is malformed.
+int network_json_get(const char *json, const char *path, char *value, size_t len, int *type);
+
+// Makes the GET requests of this app conditional (or not, forgetting what was kept): the driver keeps
+// the ETag or Last-Modified value of the body in the response buffer and the server does not send it
+// again to the next request to the same URL (up to 128 bytes long) with the same buffer; any other
+// response written to the buffer replaces it
+int network_set_conditional(bool conditional);
+// Sends a GET request with the response body going to `buffer`, zero terminated. Returns the driver
+// status and sets `http_status` to the response status code; a body of `len` bytes or more is truncated,
+// without a terminator, and reported with NETWORK_ERROR_TRUNCATED. With conditional requests,
+// NETWORK_ERROR_NOT_MODIFIED means that `buffer` still holds the latest body.
+int network_get_cached(const char *url, char *buffer, size_t len, int *http_status);
+
+// Replaces the API credential kept by the kernel: the following requests to `origin` (e.g. API_ENDPOINT)
+// get an "Authorization: Bearer <token>" header, and the token cannot be read back. An empty token
+// removes the credential. Only the app named "credential_provisioner" may call it, and only if the
+// board started in provisioning mode (on the Micro:bit, button A held at reset); it gets
+// TOCK_STATUSCODE_NOSUPPORT otherwise, like the other apps. Returns the driver status once the
+// credential is saved in flash.
+int network_provision_credential(const char *origin, const char *token);
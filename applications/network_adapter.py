@@ -1,33 +1,257 @@
 import serial
 import re
+import sys
 import socket
+import struct
+import binascii
+import threading
+import urllib.request
 from time import sleep
 
+# Channel frames on the serial link: sync | channel ID | payload length (2 bytes, LE) | payload
+# Each byte stream of Microbit (console, kernel debug output, network) goes through its own channel
+CHANNEL_SYNC = 0xC5
+CHANNEL_CONSOLE = 0
+CHANNEL_DEBUG = 1
+CHANNEL_NETWORK = 2
+CHANNEL_MAX_PAYLOAD_LEN = 0xFFFF
+
+# Response frame: sync | type | payload length | payload | CRC-16/CCITT-FALSE of type, length and payload
+FRAME_SYNC = b'\xa5\x5a'
+FRAME_RESPONSE = 0x01
+FRAME_ERROR = 0x02     # payload: one of the ERROR_* codes
+FRAME_ACCEPTED = 0x03  # payload: ID of the request (4 bytes, LE), sent as soon as the request is read
+FRAME_PUSH = 0x04      # payload: a message pushed by the server, sent at any time
+FRAME_HELLO = 0x05     # payload: protocol version | capabilities | longest request accepted (4 bytes, LE)
+FRAME_HEADER_LEN = 7
+# The kernel reads the frame header, then the payload in segments of up to the size of its buffer
+KERNEL_BUFFER_LEN = 1024
+
+# Handshake: Microbit sends "HELLO <version> <device ID> <capabilities>" (hex) and the proxy answers
+# with a hello frame; the proxy also sends one when it starts
+PROTOCOL_VERSION = 2
+CAPABILITY_PUSH = 0x01
+CAPABILITY_COMPRESSION = 0x02
+CAPABILITY_PACING = 0x04
+MAX_REQUEST_LEN = 0xFFFF
+
+# Each request is preceded by "ID <request ID>" (hex); the ID goes back in the accepted frame, and
+# Microbit takes the response or error frame that follows only if the ID is that of its ongoing request
+
+# Paced responses: "PACE <segment length>" (hex) before a request asks for the payload of its response
+# frame in segments, each sent after a "NEXT" line; any other line drops the rest of the response
+PACE_TIMEOUT = 60
+
+# Error vocabulary of the error frames
+ERROR_CONNECTION_REFUSED = 0x01
+ERROR_DNS_FAILURE = 0x02
+ERROR_TIMEOUT = 0x03
+ERROR_INVALID_REQUEST = 0x04
+ERROR_CONNECTION_FAILED = 0x05
+
+# Event stream of the server whose messages are pushed to Microbit (None to disable pushes)
+PUSH_URL = 'http://192.168.100.62:3000/api/notifications/stream'
+PUSH_RETRY_DELAY = 5
+
+
+def frame(frame_type, payload):
+    header = struct.pack('<BI', frame_type, len(payload))
+    crc = binascii.crc_hqx(header + payload, 0xFFFF)
+    return FRAME_SYNC + header + payload + struct.pack('<H', crc)
+
+
+class NetworkChannel:
+    # Bytes received on the network channel, read by the main loop
+
+    def __init__(self):
+        self.data = bytearray()
+        self.received = threading.Condition()
+
+    def feed(self, data):
+        with self.received:
+            self.data += data
+            self.received.notify_all()
+
+    def read(self, size):
+        with self.received:
+            self.received.wait_for(lambda: len(self.data) >= size)
+            data = bytes(self.data[:size])
+            del self.data[:size]
+            return data
+
+    def readline(self, timeout=None):
+        # Returns None if no line came before the timeout
+        with self.received:
+            if not self.received.wait_for(lambda: b'\n' in self.data, timeout):
+                return None
+            end = self.data.index(b'\n') + 1
+            line = bytes(self.data[:end])
+            del self.data[:end]
+            return line
+
+    def unread(self, data):
+        with self.received:
+            self.data[:0] = data
+            self.received.notify_all()
+
+
+def write_channel(channel, data):
+    with serial_lock:
+        for i in range(0, len(data), CHANNEL_MAX_PAYLOAD_LEN):
+            payload = data[i:i + CHANNEL_MAX_PAYLOAD_LEN]
+            ser.write(struct.pack('<BBH', CHANNEL_SYNC, channel, len(payload)) + payload)
+        ser.flush()
+
+
+def read_channels():
+    # Split the bytes from Microbit into its channels; the console and the debug output are printed
+    while True:
+        sync = ser.read(1)
+        if sync[0] != CHANNEL_SYNC:
+            # Bytes outside of channel frames, e.g. the output of a kernel panic
+            sys.stdout.write(sync.decode("utf-8", "replace"))
+            sys.stdout.flush()
+            continue
+        channel, length = struct.unpack('<BH', ser.read(3))
+        payload = ser.read(length)
+        if channel == CHANNEL_NETWORK:
+            network.feed(payload)
+        elif channel in (CHANNEL_CONSOLE, CHANNEL_DEBUG):
+            sys.stdout.write(payload.decode("utf-8", "replace"))
+            sys.stdout.flush()
+
+
+def forward_console_input():
+    # Lines typed in the terminal of the proxy go to the console of Microbit
+    for line in sys.stdin:
+        write_channel(CHANNEL_CONSOLE, line.encode("utf-8"))
+
+
+def send_frame(frame_type, payload):
+    data = frame(frame_type, payload)
+    segments = [data[:FRAME_HEADER_LEN]] + \
+        [data[i:i + KERNEL_BUFFER_LEN] for i in range(FRAME_HEADER_LEN, len(data), KERNEL_BUFFER_LEN)]
+    # Pushed messages are sent from another thread; frames must not interleave
+    with network_lock:
+        for segment in segments:
+            write_channel(CHANNEL_NETWORK, segment)
+            sleep(0.002)  # Small delay between segments so that Microbit can start its next read
+
+
+def send_paced_frame(frame_type, payload, segment_len):
+    data = frame(frame_type, payload)
+    segments = [data[i:i + segment_len] for i in range(FRAME_HEADER_LEN, len(data) - 2, segment_len)]
+    # The CRC goes with the last segment
+    segments = segments[:-1] + [segments[-1] + data[-2:]] if segments else [data[-2:]]
+    with network_lock:
+        write_channel(CHANNEL_NETWORK, data[:FRAME_HEADER_LEN])
+        for index, segment in enumerate(segments):
+            if index > 0:
+                line = network.readline(PACE_TIMEOUT)
+                if line is None or line.strip() != b'NEXT':
+                    print("> Response dropped by Microbit")
+                    if line is not None:
+                        network.unread(line)
+                    return
+            for i in range(0, len(segment), KERNEL_BUFFER_LEN):
+                write_channel(CHANNEL_NETWORK, segment[i:i + KERNEL_BUFFER_LEN])
+                sleep(0.002)
+
+
+def send_hello():
+    capabilities = CAPABILITY_PACING | (CAPABILITY_PUSH if PUSH_URL is not None else 0)
+    send_frame(FRAME_HELLO, struct.pack('<BBI', PROTOCOL_VERSION, capabilities, MAX_REQUEST_LEN))
+
+
+def forward_pushes():
+    # Forward each event of the server's event stream to Microbit as it arrives
+    while True:
+        try:
+            with urllib.request.urlopen(PUSH_URL) as stream:
+                print("> Listening for pushed messages")
+                for line in stream:
+                    if line.startswith(b'data:'):
+                        message = line[len(b'data:'):].strip()
+                        print("> Pushing message:", message)
+                        send_frame(FRAME_PUSH, message)
+        except Exception as e:
+            print("> Push stream error:", e)
+        sleep(PUSH_RETRY_DELAY)
+
+
 # Open serial port to Microbit
 ser = serial.Serial('/dev/ttyACM0', baudrate=115200)
 # ser = serial.Serial('/dev/cu.usbmodem14202', baudrate=115200)
+serial_lock = threading.RLock()
+network_lock = threading.RLock()
+network = NetworkChannel()
+
+threading.Thread(target=read_channels, daemon=True).start()
+threading.Thread(target=forward_console_input, daemon=True).start()
+if PUSH_URL is not None:
+    threading.Thread(target=forward_pushes, daemon=True).start()
 
+# Let Microbit know that the proxy is there, in case it already gave up on its handshake
+send_hello()
+
+segment_len = 0
+request_id = 0
 while True:
-    # Wait for input from Microbit
-    req = ser.readline()
+    # Wait for a request from Microbit
+    req = network.readline()
     req_str = req.decode("utf-8")
     print(req_str.strip())
 
+    # ID of the next request
+    id_match = re.match(r"ID ([0-9a-f]{8})", req_str)
+    if id_match:
+        request_id = int(id_match.group(1), 16)
+        continue
+
+    # The response to the next request is paced
+    pace_match = re.match(r"PACE ([0-9a-f]{8})", req_str)
+    if pace_match:
+        segment_len = int(pace_match.group(1), 16)
+        continue
+
+    # Handshake of Microbit
+    hello_match = re.match(r"HELLO ([0-9a-f]{2}) ([0-9a-f]{16}) ([0-9a-f]{2})", req_str)
+    if hello_match:
+        version = int(hello_match.group(1), 16)
+        print("> Device %s connected (protocol version %d, capabilities %#x)" %
+              (hello_match.group(2), version, int(hello_match.group(3), 16)))
+        if version != PROTOCOL_VERSION:
+            print("> Warning: the device speaks protocol version %d, the proxy speaks version %d" %
+                  (version, PROTOCOL_VERSION))
+        send_hello()
+        continue
+
     # Check if the input is an HTTP request to be performed by the proxy
-    if re.match("(GET)|(POST)", req_str):
-        if re.match("(POST)", req_str):
-            # Wait for the body
-            req += ser.readline()
-            req += ser.readline()
-            req += ser.readline()
-            req += ser.readline()
+    if re.match("(GET|POST|PUT|PATCH|DELETE|HEAD) ", req_str):
+        paced_len, segment_len = segment_len, 0
+        # Read the headers, up to the empty line that ends them
+        content_length = 0
+        while True:
+            header = network.readline()
+            req += header
+            if header.strip() == b'':
+                break
+            length_match = re.match(rb"Content-Length:\s*([0-9]+)", header, re.IGNORECASE)
+            if length_match:
+                content_length = int(length_match.group(1))
+
+        # Read the body
+        req += network.read(content_length)
+        send_frame(FRAME_ACCEPTED, struct.pack('<I', request_id))
 
         print("> Performing HTTP API request...")
-        print(req)
+        # The API token added by the kernel stays out of the terminal
+        print(re.sub(rb"(Authorization: Bearer )\S+", rb"\1***", req, flags=re.IGNORECASE))
 
         host_match = re.search("https?:\/\/([^\s:]*)(\:([0-9]*))?.*", req_str)
         if host_match is None:
             print("> Error: Invalid URL")
+            send_frame(FRAME_ERROR, bytes([ERROR_INVALID_REQUEST]))
             continue
         host = host_match.group(1)
         port = int(host_match.group(3) or 80)
@@ -38,6 +262,7 @@ while True:
         client.settimeout(1)
 
         res = b''
+        error = None
         try:
             client.connect((host, port))
             client.send(req + b'\r\n')
@@ -48,25 +273,36 @@ while True:
                 if not data:
                     break
                 res += data
+        except socket.gaierror as e:
+            print("> Error:", e)
+            error = ERROR_DNS_FAILURE
+        except ConnectionRefusedError as e:
+            print("> Error:", e)
+            error = ERROR_CONNECTION_REFUSED
+        except socket.timeout as e:
+            # Servers that keep the connection open are done once they stop sending
+            print("> Error:", e)
+            if not res:
+                error = ERROR_TIMEOUT
         except Exception as e:
-            # No response data
             print("> Error:", e)
-            pass
+            if not res:
+                error = ERROR_CONNECTION_FAILED
+        finally:
+            client.close()
+
+        if error is not None:
+            send_frame(FRAME_ERROR, bytes([error]))
+            continue
 
         print("> Received HTTP API response:\n")
         print(res)
 
         # Send the response to Microbit
-        num = 0
-        for byte in res:
-            ser.write(bytes([byte]))
-            ser.flush()
-            sleep(0.001)  # Small delay so that we don't overflow the Microbit's serial buffer
-            num += 1
-
-        # Send termination byte
-        ser.write(b'\0')
-        ser.flush()
+        if paced_len > 0:
+            send_paced_frame(FRAME_RESPONSE, res, paced_len)
+        else:
+            send_frame(FRAME_RESPONSE, res)
 
 
 ser.close()
\ No newline at end of file
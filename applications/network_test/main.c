@@ -15,6 +15,18 @@ void print_formatted_text(char* text) {
   printf("\n------------------------------------------------------------\n");
 }
 
+static char chunk_buffer[128];
+
+static void print_chunk(char* chunk, int len, bool more, void *ud)
+{
+  // The buffer has no room for the terminator when the chunk fills it
+  printf("%.*s", len, chunk);
+  if (!more)
+  {
+    printf("\n------------------------------------------------------------\n");
+  }
+}
+
 // Callback for button presses.
 //   btn_num: The index of the button associated with the callback
 //   val: 1 if pressed, 0 if depressed
@@ -24,11 +36,27 @@ static void button_callback(int btn_num, int val, int arg2, void *ud)
   {
     if (btn_num == 0)
     {
-      // Button A: perform a GET request
-      int status;
-      char* data = network_get(API_ENDPOINT, &status);
+      // Button A: perform a GET request, streaming the response if the proxy can pace it
+      int status, http_status;
+      printf("\n\nAPI response:");
+      printf("\n------------------------------------------------------------\n");
+      status = network_get_streamed(API_ENDPOINT, chunk_buffer, sizeof(chunk_buffer), print_chunk, NULL,
+                                    &http_status);
+      if (status == 0)
+      {
+        printf("HTTP status: %i\n", http_status);
+        return;
+      }
+      if (status != TOCK_STATUSCODE_NOSUPPORT)
+      {
+        printf("No response. Error code: %i\n", status);
+        return;
+      }
+
+      char* data = network_get(API_ENDPOINT, &status, &http_status);
       if (data != NULL)
       {
+        printf("HTTP status: %i\n", http_status);
         print_formatted_text(data);
         free(data);
       }
@@ -42,7 +70,8 @@ static void button_callback(int btn_num, int val, int arg2, void *ud)
       // Button B: perform a POST request
       char* body_buffer = (char*) calloc(1024, sizeof(char));
       strcpy(body_buffer, "test body");
-      network_post(API_ENDPOINT, body_buffer);
+      int http_status = network_post(API_ENDPOINT, body_buffer);
+      printf("HTTP status: %i\n", http_status);
       free(body_buffer);
     }
   }
@@ -12,6 +12,33 @@
 #include <buzzer.h>
 #include "../config.h"
 
+#define PUSH_BUFFER_SIZE 512
+#define NAME_BUFFER_SIZE 128
+#define RESPONSE_BUFFER_SIZE 1024
+// Bytes between the steps of the download spinner
+#define PROGRESS_INTERVAL 64
+// A download that receives nothing for this long is abandoned
+#define STALL_TIMEOUT_MS 3000
+
+static char push_buffer[PUSH_BUFFER_SIZE];
+// Keeps the latest notifications document, which the server does not send again while it is unchanged
+static char response_buffer[RESPONSE_BUFFER_SIZE];
+static bool pushed = false;
+static int push_status = 0;
+static bool link_changed = false;
+
+static tock_timer_t stall_timer;
+static bool stall_timer_armed = false;
+
+static void disarm_stall_timer()
+{
+    if (stall_timer_armed)
+    {
+        timer_cancel(&stall_timer);
+        stall_timer_armed = false;
+    }
+}
+
 static void print_formatted_text(char *text)
 {
     printf("\n\nAPI response:");
@@ -22,34 +49,24 @@ static void print_formatted_text(char *text)
 
 static char *substract_notification_name(char **data)
 {
-    if (*data != NULL)
+    if (*data == NULL)
     {
-        char *ptr = *data;
-
-        ptr = strstr(ptr, "\"notification\"");
-        if (ptr == NULL)
-        {
-            return NULL;
-        }
-
-        ptr = strchr(ptr, ':');
-        if (ptr == NULL)
-        {
-            return NULL;
-        }
-
-        ptr += 2;
-        char *end_ptr = ptr;
-        end_ptr = strchr(end_ptr, ',');
-        end_ptr -= 2;
-        int len = (int)(end_ptr - ptr) + 1;
+        return NULL;
+    }
 
-        char *name = (char *)calloc(len + 1, sizeof(char));
-        strncpy(name, ptr, len);
-        return name;
+    char *name = (char *)calloc(NAME_BUFFER_SIZE, sizeof(char));
+    if (name == NULL)
+    {
+        return NULL;
     }
 
-    return NULL;
+    int type;
+    if (network_json_get(*data, "notification", name, NAME_BUFFER_SIZE, &type) < 0 || type != NETWORK_JSON_STRING)
+    {
+        free(name);
+        return NULL;
+    }
+    return name;
 }
 
 static void notify()
@@ -91,22 +108,83 @@ static void notify()
     }
 }
 
+static void show_notification(char *name)
+{
+    print_formatted_text(name);
+    notify();
+    for (int i = 0; i < 3; i++) {
+        display_text(name, single, 400);
+        delay_ms(2000);
+    }
+}
+
+static void download_stalled(__attribute__ ((unused)) int now, __attribute__ ((unused)) int expiration,
+                             __attribute__ ((unused)) int unused, __attribute__ ((unused)) void *ud)
+{
+    stall_timer_armed = false;
+    printf("Notifications download stalled; giving up\n");
+    network_cancel();
+}
+
+// Shows a spinner on the display while the response comes in
+static void download_progress(int received, int total, __attribute__ ((unused)) void *ud)
+{
+    static const char spinner[] = "|/-\\";
+    static int step = 0;
+
+    char text[2] = { spinner[step++ % 4], '\0' };
+    display_text(text, single, 100);
+
+    disarm_stall_timer();
+    if (received < total)
+    {
+        timer_in(STALL_TIMEOUT_MS, download_stalled, NULL, &stall_timer);
+        stall_timer_armed = true;
+    }
+}
+
 static void get_notifications()
 {
+    network_set_progress(PROGRESS_INTERVAL, download_progress, NULL);
+    network_set_conditional(true);
+
     do
     {
-        int status;
-        char *data = network_get(API_ENDPOINT "/api/notifications", &status);
-        char *name = substract_notification_name(&data);
+        int http_status;
+        int status = network_get_cached(API_ENDPOINT "/api/notifications", response_buffer, RESPONSE_BUFFER_SIZE,
+                                        &http_status);
+        disarm_stall_timer();
+        char *name = NULL;
+        if (status == NETWORK_ERROR_NOT_MODIFIED)
+        {
+            // The notification was already shown
+        }
+        else if (status == 0 && http_status == 200)
+        {
+            char *data = response_buffer;
+            name = substract_notification_name(&data);
+        }
+        else if (status == 0 || status == NETWORK_ERROR_TRUNCATED)
+        {
+            printf("Notifications request failed with HTTP status %d\n", http_status);
+        }
+        else if (status == NETWORK_ERROR_PROXY_UNREACHABLE || status == TOCK_STATUSCODE_OFF)
+        {
+            // Nothing to retry until the proxy is started; signal it on the display
+            printf("Network proxy unreachable\n");
+            display_text("!", single, 400);
+        }
+        else if (status == NETWORK_ERROR_TIMEOUT || status == NETWORK_ERROR_CONNECTION_FAILED)
+        {
+            // Transient failure; retry soon instead of waiting for the next poll
+            printf("Notifications request failed (%#x); retrying\n", status);
+            delay_ms(5000);
+            continue;
+        }
 
         if (name != NULL)
         {
-            print_formatted_text(name);
-            notify();
-            for (int i = 0; i < 3; i++) {
-                display_text(name, single, 400);
-                delay_ms(2000);
-            }
+            show_notification(name);
             free(name);
         }
         else
@@ -114,16 +192,84 @@ static void get_notifications()
             printf("No new notification\n");
         }
 
-        free(data);
         delay_ms(35000);
     } while (true);
 }
 
+static void notification_pushed(int status, __attribute__ ((unused)) int len, __attribute__ ((unused)) void *ud)
+{
+    push_status = status;
+    pushed = true;
+}
+
+// The server pushes each notification when it is due
+static void wait_for_notifications()
+{
+    do
+    {
+        pushed = false;
+        yield_for(&pushed);
+        if (push_status != 0)
+        {
+            printf("Pushed notification dropped (%#x)\n", push_status);
+            continue;
+        }
+
+        char *data = push_buffer;
+        char *name = substract_notification_name(&data);
+        if (name != NULL)
+        {
+            show_notification(name);
+            free(name);
+        }
+    } while (true);
+}
+
+static void link_state_changed(__attribute__ ((unused)) int state, __attribute__ ((unused)) int proxy,
+                               __attribute__ ((unused)) int max_request_len, __attribute__ ((unused)) void *ud)
+{
+    link_changed = true;
+}
+
+// Whether the proxy forwards the notifications the server pushes
+static bool proxy_pushes()
+{
+    int state, proxy, max_request_len;
+    if (network_link_state(&state, &proxy, &max_request_len) < 0)
+    {
+        return false;
+    }
+
+    // The handshake may still be going on at boot
+    if (state == NETWORK_LINK_HANDSHAKING && network_subscribe_link(link_state_changed, NULL) == 0)
+    {
+        while (state == NETWORK_LINK_HANDSHAKING)
+        {
+            link_changed = false;
+            yield_for(&link_changed);
+            if (network_link_state(&state, &proxy, &max_request_len) < 0)
+            {
+                break;
+            }
+        }
+        network_subscribe_link(NULL, NULL);
+    }
+    return state == NETWORK_LINK_UP && (proxy & NETWORK_PROXY_PUSH) != 0;
+}
+
 int main(void)
 {
     if (driver_exists(DRIVER_NUM_NETWORK) && driver_exists(DRIVER_NUM_LED_TEXT))
     {
-        get_notifications();
+        if (proxy_pushes() && network_subscribe_push(push_buffer, PUSH_BUFFER_SIZE, notification_pushed, NULL) == 0)
+        {
+            wait_for_notifications();
+        }
+        else
+        {
+            // Fall back to polling the server
+            get_notifications();
+        }
     }
     else
     {
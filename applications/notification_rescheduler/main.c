@@ -105,7 +105,11 @@ static void button_callback(int btn_num, int val, int arg2, void *ud)
                 char *body = (char *)calloc(33 + 15, sizeof(char));
                 sprintf(body, "{\"snooze\":%d}", snooze_value);
 
-                network_post(API_ENDPOINT "/api/snooze", body);
+                // Kept in the offline queue if the server cannot be reached
+                if (network_post_or_queue(API_ENDPOINT "/api/snooze", body, snooze_value) == 0)
+                {
+                    printf("Server unreachable; snooze queued\n");
+                }
                 reset();
                 free(body);
             }
@@ -120,10 +124,18 @@ static void button_callback(int btn_num, int val, int arg2, void *ud)
     processing = false;
 }
 
+// Callback for the queued snooze requests that were sent, or dropped after an error
+static void snooze_flushed(int tag, int status, int http_status, void *ud)
+{
+    printf("Queued snooze of %ds sent: status %#x, HTTP status %d\n", tag, status, http_status);
+}
+
 int main(void)
 {
     if (driver_exists(DRIVER_NUM_NETWORK) && driver_exists(DRIVER_NUM_LED_TEXT))
     {
+        network_subscribe_flushed(snooze_flushed, NULL);
+
         // Register actions on buttons
         button_subscribe(button_callback, NULL);
         button_enable_interrupt(0);
@@ -0,0 +1,264 @@
+//! API credential kept in flash
+//!
+//! The credential is a bearer token and the origin it is for (scheme, host and port, e.g.
+//! "http://192.168.100.62:3000"). `crate::network::Network` adds the token as an
+//! `Authorization` header to the requests to that origin; the processes never see it.
+//!
+//! `FlashCredential` keeps the credential in a flash page of its own and loads it at boot.
+//! A process provisions it through the `NetworkDriver` (see `crate::network_driver`), and
+//! `ProvisioningFilter` lets only the provisioning process do so, in provisioning mode.
+//!
+//! Page layout:
+//!     magic "cred" (4 bytes) | origin length (1 byte) | token length (2 bytes, LE) | origin | token
+//!
+//! Usage (board `main.rs`):
+//!     let credential = static_init!(
+//!         FlashCredential<'static, FlashUser<'static, Nvmc>>,
+//!         FlashCredential::new(flash_user, page_number, page_buffer)
+//!     );
+//!     flash_user.set_client(credential);
+//!     credential.load();
+//!     network.set_credential(credential);
+//!     network_driver.set_credential_store(credential);
+//!     credential.set_client(network_driver);
+
+use core::cell::Cell;
+
+use kernel::hil::flash::{self, Flash};
+use kernel::platform::SyscallFilter;
+use kernel::process::Process;
+use kernel::syscall::Syscall;
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::network_driver;
+
+pub const MAX_ORIGIN_LEN: usize = 64;
+pub const MAX_TOKEN_LEN: usize = 256;
+
+const MAGIC: &[u8] = b"cred";
+// Magic, origin length and token length
+const HEADER_LEN: usize = 7;
+
+/// Gives the credential to the network
+pub trait Credential {
+    /// Call `f` with the origin and the token, if a credential is set
+    fn with_credential(&self, f: &mut dyn FnMut(&[u8], &[u8]));
+}
+
+/// Keeps the credential set by the provisioning process
+pub trait CredentialStore<'a> {
+    fn set_client(&self, client: &'a dyn CredentialClient);
+
+    /// Replace the credential; an empty token removes it
+    ///
+    /// The new credential is used right away and `CredentialClient::provisioned` reports
+    /// whether it was saved. Fails with INVAL if the origin is empty or either of them has
+    /// bytes other than visible ASCII characters, with SIZE if either is too long and with
+    /// BUSY while the credential is being loaded or saved.
+    fn provision(&self, origin: &[u8], token: &[u8]) -> Result<(), ErrorCode>;
+}
+
+pub trait CredentialClient {
+    fn provisioned(&self, result: Result<(), ErrorCode>);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    Loading,
+    Saving,
+}
+
+pub struct FlashCredential<'a, F: Flash + 'static> {
+    flash: &'a F,
+    page_number: usize,
+    page: TakeCell<'static, F::Page>,
+    client: OptionalCell<&'a dyn CredentialClient>,
+    state: Cell<State>,
+    origin: MapCell<[u8; MAX_ORIGIN_LEN]>,
+    origin_len: Cell<usize>,
+    // Empty if no credential is set
+    token: MapCell<[u8; MAX_TOKEN_LEN]>,
+    token_len: Cell<usize>,
+}
+
+impl<'a, F: Flash + 'static> FlashCredential<'a, F> {
+    pub fn new(flash: &'a F, page_number: usize, page: &'static mut F::Page) -> FlashCredential<'a, F> {
+        FlashCredential {
+            flash,
+            page_number,
+            page: TakeCell::new(page),
+            client: OptionalCell::empty(),
+            state: Cell::new(State::Idle),
+            origin: MapCell::new([0; MAX_ORIGIN_LEN]),
+            origin_len: Cell::new(0),
+            token: MapCell::new([0; MAX_TOKEN_LEN]),
+            token_len: Cell::new(0),
+        }
+    }
+
+    /// Read the credential saved before the last reboot
+    pub fn load(&self) {
+        if self.state.get() != State::Idle {
+            return;
+        }
+        if let Some(page) = self.page.take() {
+            match self.flash.read_page(self.page_number, page) {
+                Ok(()) => self.state.set(State::Loading),
+                Err((_error, page)) => {
+                    self.page.replace(page);
+                }
+            }
+        }
+    }
+
+    fn set_credential(&self, origin: &[u8], token: &[u8]) {
+        self.origin.map(|buffer| buffer[..origin.len()].copy_from_slice(origin));
+        self.origin_len.set(origin.len());
+        self.token.map(|buffer| buffer[..token.len()].copy_from_slice(token));
+        self.token_len.set(token.len());
+    }
+
+    /// Take the credential of a page read from the flash, if it holds one
+    fn read_credential(&self, page: &[u8]) {
+        if page.len() < HEADER_LEN || &page[..MAGIC.len()] != MAGIC {
+            // Erased or never written
+            return;
+        }
+        let origin_len = page[4] as usize;
+        let token_len = u16::from_le_bytes([page[5], page[6]]) as usize;
+        if origin_len > MAX_ORIGIN_LEN || token_len > MAX_TOKEN_LEN || HEADER_LEN + origin_len + token_len > page.len()
+        {
+            return;
+        }
+        let origin_end = HEADER_LEN + origin_len;
+        self.set_credential(&page[HEADER_LEN..origin_end], &page[origin_end..origin_end + token_len]);
+    }
+}
+
+/// Whether the bytes can go into a header line unchanged
+fn is_visible_ascii(data: &[u8]) -> bool {
+    data.iter().all(|&c| c > ' ' as u8 && c <= '~' as u8)
+}
+
+impl<'a, F: Flash + 'static> Credential for FlashCredential<'a, F> {
+    fn with_credential(&self, f: &mut dyn FnMut(&[u8], &[u8])) {
+        let (origin_len, token_len) = (self.origin_len.get(), self.token_len.get());
+        if token_len == 0 {
+            return;
+        }
+        self.origin.map(|origin| {
+            self.token.map(|token| f(&origin[..origin_len], &token[..token_len]));
+        });
+    }
+}
+
+impl<'a, F: Flash + 'static> CredentialStore<'a> for FlashCredential<'a, F> {
+    fn set_client(&self, client: &'a dyn CredentialClient) {
+        self.client.set(client);
+    }
+
+    fn provision(&self, origin: &[u8], token: &[u8]) -> Result<(), ErrorCode> {
+        // Without a token, the origin does not matter
+        let origin = if token.is_empty() { &origin[..0] } else { origin };
+        if origin.len() > MAX_ORIGIN_LEN || token.len() > MAX_TOKEN_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if (origin.is_empty() && !token.is_empty()) || !is_visible_ascii(origin) || !is_visible_ascii(token) {
+            return Err(ErrorCode::INVAL);
+        }
+        if self.state.get() != State::Idle {
+            return Err(ErrorCode::BUSY);
+        }
+        let page = self.page.take().ok_or(ErrorCode::BUSY)?;
+
+        let data = page.as_mut();
+        let origin_end = HEADER_LEN + origin.len();
+        if origin_end + token.len() > data.len() {
+            self.page.replace(page);
+            return Err(ErrorCode::SIZE);
+        }
+        data[..MAGIC.len()].copy_from_slice(MAGIC);
+        data[4] = origin.len() as u8;
+        data[5..7].copy_from_slice(&(token.len() as u16).to_le_bytes());
+        data[HEADER_LEN..origin_end].copy_from_slice(origin);
+        data[origin_end..origin_end + token.len()].copy_from_slice(token);
+
+        match self.flash.write_page(self.page_number, page) {
+            Ok(()) => {
+                self.state.set(State::Saving);
+                self.set_credential(origin, token);
+                Ok(())
+            }
+            Err((error, page)) => {
+                self.page.replace(page);
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<'a, F: Flash + 'static> flash::Client<F> for FlashCredential<'a, F> {
+    fn read_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        if matches!(error, flash::Error::CommandComplete) {
+            self.read_credential(page.as_mut());
+        }
+        self.page.replace(page);
+        self.state.set(State::Idle);
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        self.page.replace(page);
+        self.state.set(State::Idle);
+        let result = match error {
+            flash::Error::CommandComplete => Ok(()),
+            flash::Error::FlashError => Err(ErrorCode::FAIL),
+        };
+        self.client.map(|client| client.provisioned(result));
+    }
+
+    fn erase_complete(&self, _error: flash::Error) {}
+}
+
+/// Lets only the process named `process_name` provision the credential, and only if the board
+/// started in provisioning mode
+///
+/// Boards that keep a credential use it as their `SyscallFilter`; the other processes, and
+/// all of them outside of provisioning mode, get NOSUPPORT for the provisioning command of the
+/// `NetworkDriver`.
+///
+/// The name comes from the TBF header of the process, which any app can declare, so it cannot
+/// be the only check: the board decides at boot whether it is in provisioning mode from
+/// something that no app controls, e.g. a button held while it starts.
+pub struct ProvisioningFilter {
+    process_name: &'static str,
+    provisioning: bool,
+}
+
+impl ProvisioningFilter {
+    pub fn new(process_name: &'static str, provisioning: bool) -> ProvisioningFilter {
+        ProvisioningFilter {
+            process_name,
+            provisioning,
+        }
+    }
+}
+
+impl SyscallFilter for ProvisioningFilter {
+    fn filter_syscall(&self, process: &dyn Process, syscall: &Syscall) -> Result<(), ErrorCode> {
+        match syscall {
+            Syscall::Command {
+                driver_number,
+                subdriver_number,
+                ..
+            } if *driver_number == network_driver::DRIVER_NUM
+                && *subdriver_number == network_driver::PROVISION_COMMAND
+                && (!self.provisioning || process.get_process_name() != self.process_name) =>
+            {
+                Err(ErrorCode::NOSUPPORT)
+            }
+            _ => Ok(()),
+        }
+    }
+}
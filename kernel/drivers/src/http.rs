@@ -0,0 +1,576 @@
+//! Interface for issuing HTTP requests from the kernel
+//!
+//! `drivers::network::Network` implements `Http` over the serial proxy. Several users
+//! share it through `drivers::virtual_http::MuxHttp`; each user (the `NetworkDriver` of
+//! the processes or another capsule) gets a `VirtualHttpDevice`.
+//!
+//! A request goes as follows:
+//!     1. the user calls `Http::request` with the method, the content type and the timeout
+//!     2. once it is its turn to be sent, `HttpClient::write_request` asks the user to write
+//!        the address, the extra headers and the body with the `RequestWriter`
+//!     3. the status line and the headers arrive through `HttpClient::response_headers`,
+//!        the decoded body through `HttpClient::response_body`; `HttpClient::response_progress`
+//!        tells how much of the response arrived
+//!     4. `HttpClient::request_done` ends the request with the status code and the body length
+//!
+//! A client that already has a response can make the next request to the same address
+//! conditional with `RequestWriter::write_condition`, giving the `Validator` that came with
+//! `HttpResponse`; the server then answers 304 Not Modified, without a body, if the response
+//! did not change.
+//!
+//! A client that cannot take the whole response at once asks for it in segments with
+//! `RequestWriter::set_segment_len`; after each segment, `HttpClient::segment_done` may hold
+//! the next one until the client calls `Http::next_segment`.
+//!
+//! E.g. a capsule that fetches a page:
+//!     impl HttpClient for Fetcher {
+//!         fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+//!             request.write_address("http://example.com/api".as_bytes())
+//!         }
+//!         fn response_headers(&self, _offset: usize, _data: &[u8]) {}
+//!         fn response_body(&self, offset: usize, data: &[u8]) { /* copy into its buffer */ }
+//!         fn request_done(&self, result: Result<HttpResponse, HttpError>) { ... }
+//!     }
+
+use enum_primitive::enum_from_primitive;
+
+use kernel::errorcode::into_statuscode;
+use kernel::processbuffer::ReadableProcessSlice;
+use kernel::ErrorCode;
+
+enum_from_primitive! {
+    /// HTTP request methods
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum Method {
+        Get = 0,
+        Post = 1,
+        Put = 2,
+        Patch = 3,
+        Delete = 4,
+        Head = 5,
+    }
+}
+
+impl Method {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+        }
+    }
+
+    /// Whether a request with this method may carry a body
+    pub fn allows_body(&self) -> bool {
+        match self {
+            Method::Get | Method::Head => false,
+            Method::Post | Method::Put | Method::Patch | Method::Delete => true,
+        }
+    }
+}
+
+enum_from_primitive! {
+    /// Content type of the request body
+    #[derive(Copy, Clone, PartialEq, Debug)]
+    pub enum ContentType {
+        Json = 0,
+        Form = 1,
+        Text = 2,
+        OctetStream = 3,
+        /// The `Content-Type` header is given in the extra headers
+        Custom = 4,
+    }
+}
+
+impl ContentType {
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            ContentType::Json => Some("application/json"),
+            ContentType::Form => Some("application/x-www-form-urlencoded"),
+            ContentType::Text => Some("text/plain"),
+            ContentType::OctetStream => Some("application/octet-stream"),
+            ContentType::Custom => None,
+        }
+    }
+}
+
+/// Failures of a request on the way to the server and back
+///
+/// The values start above those of `ErrorCode` so that both fit in the status of an upcall.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum NetworkError {
+    ProxyUnreachable = 0x100,
+    ConnectionRefused = 0x101,
+    DnsFailure = 0x102,
+    MalformedResponse = 0x103,
+    Timeout = 0x104,
+    Truncated = 0x105,
+    InvalidRequest = 0x106,
+    ConnectionFailed = 0x107,
+    /// The request could not reach the server and was kept in the offline queue
+    Queued = 0x108,
+    /// The response did not change since the one whose validator the request carried
+    NotModified = 0x109,
+}
+
+impl From<NetworkError> for usize {
+    fn from(error: NetworkError) -> usize {
+        error as usize
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HttpError {
+    /// The request failed in the kernel, e.g. CANCEL if it was canceled
+    Kernel(ErrorCode),
+    Network(NetworkError),
+}
+
+impl HttpError {
+    /// Whether the request failed before reaching the server, so that sending it again later
+    /// may succeed without repeating it (a timed out request may have reached the server)
+    ///
+    /// OFF is the error of the requests sent while no compatible proxy is connected.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            HttpError::Kernel(ErrorCode::OFF)
+            | HttpError::Network(NetworkError::ProxyUnreachable)
+            | HttpError::Network(NetworkError::ConnectionRefused)
+            | HttpError::Network(NetworkError::DnsFailure)
+            | HttpError::Network(NetworkError::ConnectionFailed) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<HttpError> for usize {
+    /// Status reported to processes
+    fn from(error: HttpError) -> usize {
+        match error {
+            HttpError::Kernel(error) => into_statuscode(Err(error)),
+            HttpError::Network(error) => error.into(),
+        }
+    }
+}
+
+/// Longest validator kept for conditional requests
+pub const VALIDATOR_LEN: usize = 48;
+
+/// Header of a response that identifies its version
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ValidatorKind {
+    /// `ETag`, sent back in `If-None-Match`
+    ETag,
+    /// `Last-Modified`, sent back in `If-Modified-Since`
+    LastModified,
+}
+
+impl ValidatorKind {
+    /// Request header that makes a request conditional on this validator
+    pub fn condition_header(&self) -> &'static str {
+        match self {
+            ValidatorKind::ETag => "If-None-Match",
+            ValidatorKind::LastModified => "If-Modified-Since",
+        }
+    }
+}
+
+/// Value of the `ETag` or `Last-Modified` header of a response
+#[derive(Copy, Clone, Debug)]
+pub struct Validator {
+    pub kind: ValidatorKind,
+    value: [u8; VALIDATOR_LEN],
+    len: usize,
+}
+
+impl Validator {
+    /// Returns None if the value is empty, longer than `VALIDATOR_LEN` or not printable
+    pub fn new(kind: ValidatorKind, value: &[u8]) -> Option<Validator> {
+        let printable = value.iter().all(|&c| c >= ' ' as u8 && c <= '~' as u8);
+        if value.is_empty() || value.len() > VALIDATOR_LEN || !printable {
+            return None;
+        }
+        let mut validator = Validator {
+            kind,
+            value: [0; VALIDATOR_LEN],
+            len: value.len(),
+        };
+        validator.value[..value.len()].copy_from_slice(value);
+        Some(validator)
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value[..self.len]
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: usize,
+    /// Length of the status line and the headers, without the empty line that ends them
+    pub headers_len: usize,
+    /// Length of the decoded body
+    pub body_len: usize,
+    /// The `ETag` of the response or, without one, its `Last-Modified` date
+    pub validator: Option<Validator>,
+}
+
+/// Sends HTTP requests, one at a time
+pub trait Http<'a> {
+    fn set_client(&self, client: &'a dyn HttpClient);
+
+    /// Start a request; `timeout_ms` is the time the server has to answer it (0 for the default)
+    ///
+    /// Fails with BUSY if a request is ongoing.
+    fn request(&self, method: Method, content_type: ContentType, timeout_ms: u32) -> Result<(), ErrorCode>;
+
+    /// Abandon the request
+    ///
+    /// Returns true if the request was already sent; `request_done` then reports the
+    /// CANCEL error. A request still waiting for its turn is dropped without `request_done`.
+    /// Fails with ALREADY if there is no request to cancel.
+    fn cancel(&self) -> Result<bool, ErrorCode>;
+
+    /// Receive the next segment of the response, held by `HttpClient::segment_done`
+    ///
+    /// Fails with ALREADY if no segment is held.
+    fn next_segment(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait HttpClient {
+    /// Write the address, and optionally the extra headers and the body, of the request
+    ///
+    /// If this fails, the request fails with the same error, returned by `Http::request`
+    /// or reported by `request_done`.
+    fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode>;
+
+    /// Part of the status line and the headers of the response; `offset` is the index of `data[0]`
+    fn response_headers(&self, offset: usize, data: &[u8]);
+
+    /// Part of the decoded body of the response; `offset` is the index of `data[0]`
+    fn response_body(&self, offset: usize, data: &[u8]);
+
+    /// Number of bytes of the response received so far, out of `total`, as they come in
+    ///
+    /// The counts are those of the response as sent by the server, before the body is decoded.
+    fn response_progress(&self, _received: usize, _total: usize) {}
+
+    /// A segment of the response ended, if the request asked for segments; return false to
+    /// hold the next segment until `Http::next_segment`
+    ///
+    /// The request timeout does not run while a segment is held.
+    fn segment_done(&self) -> bool {
+        true
+    }
+
+    fn request_done(&self, result: Result<HttpResponse, HttpError>);
+}
+
+/// Receives the messages that the server pushes through the proxy, outside of any request
+pub trait PushClient {
+    /// Part of a pushed message; `offset` is the index of `data[0]`
+    fn push_data(&self, offset: usize, data: &[u8]);
+
+    /// End of a pushed message: its length, or MalformedResponse if it was corrupted
+    /// on the serial link
+    fn push_done(&self, result: Result<usize, NetworkError>);
+}
+
+/// Bytes of a request, from a kernel buffer or a process buffer
+pub trait RequestData {
+    fn data_len(&self) -> usize;
+    fn byte(&self, index: usize) -> u8;
+    fn copy_into(&self, dest: &mut [u8]);
+}
+
+impl RequestData for [u8] {
+    fn data_len(&self) -> usize {
+        self.len()
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        self[index]
+    }
+
+    fn copy_into(&self, dest: &mut [u8]) {
+        dest.copy_from_slice(self);
+    }
+}
+
+impl RequestData for ReadableProcessSlice {
+    fn data_len(&self) -> usize {
+        self.len()
+    }
+
+    fn byte(&self, index: usize) -> u8 {
+        self[index].get()
+    }
+
+    fn copy_into(&self, dest: &mut [u8]) {
+        self.copy_to_slice(dest);
+    }
+}
+
+/// Sequential writer of a request into the kernel buffer
+///
+/// Requests are written as <METHOD> address\r\n<headers>\r\n\r\n<body>. The address
+/// comes first, then the extra headers and the body, each at most once.
+pub struct RequestWriter<'b> {
+    buffer: &'b mut [u8],
+    len: usize,
+    method: Method,
+    content_type: ContentType,
+    has_body: bool,
+    // The request was copied whole, see `write_rendered`
+    complete: bool,
+    segment_len: usize,
+}
+
+impl<'b> RequestWriter<'b> {
+    pub(crate) fn new(buffer: &'b mut [u8], method: Method, content_type: ContentType) -> RequestWriter<'b> {
+        RequestWriter {
+            buffer,
+            len: 0,
+            method,
+            content_type,
+            has_body: false,
+            complete: false,
+            segment_len: 0,
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+
+    /// Receive the response in segments of `segment_len` bytes (0 for the whole response at once)
+    ///
+    /// The length counts the bytes of the response as sent by the server, before the body is
+    /// decoded, so a segment never brings more than `segment_len` bytes of headers and body.
+    pub fn set_segment_len(&mut self, segment_len: usize) {
+        self.segment_len = segment_len;
+    }
+
+    pub(crate) fn segment_len(&self) -> usize {
+        self.segment_len
+    }
+
+    /// Write the request line
+    ///
+    /// The address goes into the request line as it is, so it is refused (INVAL) if it is
+    /// empty or has bytes other than visible ASCII characters: a space, a line break or a
+    /// control byte would let it add headers or lines to the request.
+    pub fn write_address<D: RequestData + ?Sized>(&mut self, address: &D) -> Result<(), ErrorCode> {
+        if self.len > 0 {
+            return Err(ErrorCode::ALREADY);
+        }
+        if address.data_len() == 0 || !is_visible_ascii(address) {
+            return Err(ErrorCode::INVAL);
+        }
+        self.write(self.method.name().as_bytes())?;
+        self.write(" ".as_bytes())?;
+        self.write_data(address)?;
+        self.write("\r\n".as_bytes())
+    }
+
+    /// Write the extra headers ("Name: value" lines separated by "\r\n")
+    ///
+    /// The framing headers are set by the driver, so `Content-Length` and `Transfer-Encoding`
+    /// are refused, and so is `Content-Type`, unless the content type is custom.
+    pub fn write_headers<D: RequestData + ?Sized>(&mut self, headers: &D) -> Result<(), ErrorCode> {
+        if self.len == 0 || self.has_body || self.complete {
+            return Err(ErrorCode::INVAL);
+        }
+        validate_headers(headers, self.content_type == ContentType::Custom)?;
+        self.write_data(headers)?;
+        if !ends_with_line_break(headers) {
+            self.write("\r\n".as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write the header that makes the request conditional: the server answers 304 Not Modified,
+    /// without a body, if the response did not change since the one that came with `validator`
+    pub fn write_condition(&mut self, validator: &Validator) -> Result<(), ErrorCode> {
+        if self.len == 0 || self.has_body || self.complete {
+            return Err(ErrorCode::INVAL);
+        }
+        self.write(validator.kind.condition_header().as_bytes())?;
+        self.write(": ".as_bytes())?;
+        self.write(validator.value())?;
+        self.write("\r\n".as_bytes())
+    }
+
+    /// Write the body, after its Content-Type and Content-Length headers
+    pub fn write_body<D: RequestData + ?Sized>(&mut self, body: &D) -> Result<(), ErrorCode> {
+        if self.len == 0 || self.has_body || self.complete || !self.method.allows_body() {
+            return Err(ErrorCode::INVAL);
+        }
+        if let Some(content_type) = self.content_type.name() {
+            self.write("Content-Type: ".as_bytes())?;
+            self.write(content_type.as_bytes())?;
+            self.write("\r\n".as_bytes())?;
+        }
+        self.write("Content-Length: ".as_bytes())?;
+        self.write_decimal(body.data_len())?;
+        self.write("\r\n\r\n".as_bytes())?;
+        self.write_data(body)?;
+        self.has_body = true;
+        Ok(())
+    }
+
+    /// Write a whole request, as ended by `finish` of another writer with the same method
+    pub(crate) fn write_rendered(&mut self, request: &[u8]) -> Result<(), ErrorCode> {
+        if self.len > 0 {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.write(request)?;
+        self.complete = true;
+        Ok(())
+    }
+
+    /// End the request; returns its length
+    pub(crate) fn finish(&mut self) -> Result<usize, ErrorCode> {
+        if self.len == 0 {
+            // No address
+            return Err(ErrorCode::INVAL);
+        }
+        if !self.has_body && !self.complete {
+            self.write("\r\n".as_bytes())?;
+        }
+        Ok(self.len)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), ErrorCode> {
+        self.write_data(data)
+    }
+
+    fn write_data<D: RequestData + ?Sized>(&mut self, data: &D) -> Result<(), ErrorCode> {
+        let data_len = data.data_len();
+        if self.len + data_len > self.buffer.len() {
+            return Err(ErrorCode::SIZE);
+        }
+        data.copy_into(&mut self.buffer[self.len..self.len + data_len]);
+        self.len += data_len;
+        Ok(())
+    }
+
+    fn write_decimal(&mut self, mut value: usize) -> Result<(), ErrorCode> {
+        let mut digits = [0u8; 20];
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = (value % 10) as u8 + '0' as u8;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        self.write(&digits[start..])
+    }
+}
+
+/// Whether the bytes can go into the request line unchanged
+fn is_visible_ascii<D: RequestData + ?Sized>(data: &D) -> bool {
+    (0..data.data_len()).all(|i| data.byte(i) > ' ' as u8 && data.byte(i) <= '~' as u8)
+}
+
+fn ends_with_line_break<D: RequestData + ?Sized>(data: &D) -> bool {
+    let len = data.data_len();
+    len >= 2 && data.byte(len - 2) == '\r' as u8 && data.byte(len - 1) == '\n' as u8
+}
+
+/// Check that the extra headers are `Name: value` lines separated by "\r\n"
+fn validate_headers<D: RequestData + ?Sized>(headers: &D, allow_content_type: bool) -> Result<(), ErrorCode> {
+    let len = headers.data_len();
+    let mut line_start = 0;
+    while line_start < len {
+        // Header name
+        let mut i = line_start;
+        while i < len && is_token_char(headers.byte(i)) {
+            i += 1;
+        }
+        if i == line_start || i == len || headers.byte(i) != ':' as u8 {
+            return Err(ErrorCode::INVAL);
+        }
+        let name_end = i;
+
+        if header_name_is(headers, line_start, name_end, "Content-Length")
+            || header_name_is(headers, line_start, name_end, "Transfer-Encoding")
+            || (!allow_content_type && header_name_is(headers, line_start, name_end, "Content-Type"))
+        {
+            return Err(ErrorCode::INVAL);
+        }
+
+        // Header value; only a complete "\r\n" may end it
+        i += 1;
+        while i < len {
+            let c = headers.byte(i);
+            if c == '\r' as u8 {
+                if i + 1 < len && headers.byte(i + 1) == '\n' as u8 {
+                    break;
+                }
+                return Err(ErrorCode::INVAL);
+            }
+            if c != '\t' as u8 && (c < ' ' as u8 || c > '~' as u8) {
+                return Err(ErrorCode::INVAL);
+            }
+            i += 1;
+        }
+        line_start = i + 2;
+    }
+    Ok(())
+}
+
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".as_bytes().contains(&c)
+}
+
+fn header_name_is<D: RequestData + ?Sized>(headers: &D, start: usize, end: usize, name: &str) -> bool {
+    end - start == name.len()
+        && name
+            .as_bytes()
+            .iter()
+            .enumerate()
+            .all(|(i, c)| headers.byte(start + i).eq_ignore_ascii_case(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_line() {
+        let mut buffer = [0; 64];
+        let mut request = RequestWriter::new(&mut buffer, Method::Get, ContentType::Json);
+        assert_eq!(request.write_address("http://example.com/api?a=1".as_bytes()), Ok(()));
+        let len = request.finish().unwrap();
+        assert_eq!(&buffer[..len], "GET http://example.com/api?a=1\r\n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn address_cannot_add_lines() {
+        let addresses: [&[u8]; 6] = [
+            b"",
+            b"http://example.com/ HTTP/1.1",
+            b"http://example.com/\r\nContent-Length: 0",
+            b"x\r\nID 00000002\r\nGET http://evil/",
+            b"http://example.com/\n",
+            b"http://example.com/\x00",
+        ];
+        for address in addresses.iter() {
+            let mut buffer = [0; 64];
+            let mut request = RequestWriter::new(&mut buffer, Method::Get, ContentType::Json);
+            assert_eq!(request.write_address(*address), Err(ErrorCode::INVAL));
+            // Nothing was written
+            assert_eq!(request.finish(), Err(ErrorCode::INVAL));
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! JSON field extraction
+//!
+//! Finds the value at a key path in a JSON document, in one pass over its bytes and without
+//! allocating, so that it works on process buffers. A path is a list of object keys and array
+//! indices, e.g. `notification` or `items[0].name` (keys cannot contain `.` or `[`; an empty
+//! path is the whole document).
+//!
+//! The value goes to a sink one byte at a time: strings unescaped (without the quotes, `\uXXXX`
+//! as UTF-8), the other values as they are written in the document.
+//!
+//! E.g.:
+//!     let mut len = 0;
+//!     let value_type = json::extract(body.iter().copied(), b"items[0].name", |byte| {
+//!         if len < buffer.len() {
+//!             buffer[len] = byte;
+//!         }
+//!         len += 1;
+//!     })?;
+
+use core::iter::Peekable;
+
+use kernel::ErrorCode;
+
+/// Longest key path, in bytes
+pub const MAX_PATH_LEN: usize = 64;
+
+/// Type of an extracted value
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ValueType {
+    String = 0,
+    Number = 1,
+    Bool = 2,
+    Null = 3,
+    Object = 4,
+    Array = 5,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JsonError {
+    /// The path has an empty key or an unclosed or non numeric index
+    InvalidPath,
+    /// A key or an index of the path is not in the document
+    NotFound,
+    /// The document is not valid JSON where it was read, or ends before the value
+    Malformed,
+}
+
+impl From<JsonError> for ErrorCode {
+    fn from(error: JsonError) -> ErrorCode {
+        match error {
+            JsonError::InvalidPath => ErrorCode::INVAL,
+            JsonError::NotFound => ErrorCode::FAIL,
+            JsonError::Malformed => ErrorCode::INVAL,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Step<'p> {
+    Key(&'p [u8]),
+    Index(usize),
+}
+
+/// The steps of a key path
+struct PathSteps<'p> {
+    path: &'p [u8],
+    first: bool,
+}
+
+impl<'p> PathSteps<'p> {
+    fn new(path: &'p [u8]) -> PathSteps<'p> {
+        PathSteps { path, first: true }
+    }
+}
+
+impl<'p> Iterator for PathSteps<'p> {
+    type Item = Result<Step<'p>, JsonError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.first;
+        self.first = false;
+        match self.path {
+            [] => None,
+            [b'[', rest @ ..] => {
+                let end = match rest.iter().position(|&byte| byte == b']') {
+                    Some(end) if end > 0 => end,
+                    _ => return Some(Err(JsonError::InvalidPath)),
+                };
+                let mut index: usize = 0;
+                for &digit in &rest[..end] {
+                    if !digit.is_ascii_digit() {
+                        return Some(Err(JsonError::InvalidPath));
+                    }
+                    let digit = (digit - b'0') as usize;
+                    index = match index.checked_mul(10).and_then(|index| index.checked_add(digit)) {
+                        Some(index) => index,
+                        None => return Some(Err(JsonError::InvalidPath)),
+                    };
+                }
+                self.path = &rest[end + 1..];
+                Some(Ok(Step::Index(index)))
+            }
+            path => {
+                // Keys after the first step follow a dot
+                let key = match (first, path) {
+                    (true, _) => path,
+                    (false, [b'.', rest @ ..]) => rest,
+                    (false, _) => return Some(Err(JsonError::InvalidPath)),
+                };
+                let end = key
+                    .iter()
+                    .position(|&byte| byte == b'.' || byte == b'[')
+                    .unwrap_or(key.len());
+                if end == 0 {
+                    return Some(Err(JsonError::InvalidPath));
+                }
+                self.path = &key[end..];
+                Some(Ok(Step::Key(&key[..end])))
+            }
+        }
+    }
+}
+
+struct Parser<I: Iterator<Item = u8>> {
+    bytes: Peekable<I>,
+}
+
+impl<I: Iterator<Item = u8>> Parser<I> {
+    /// The next byte that is not whitespace, without consuming it
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(&byte) = self.bytes.peek() {
+            if !matches!(byte, b' ' | b'\t' | b'\r' | b'\n') {
+                return Some(byte);
+            }
+            self.bytes.next();
+        }
+        None
+    }
+
+    /// Consume the next byte that is not whitespace, if it is `expected`
+    fn consume(&mut self, expected: u8) -> bool {
+        if self.peek() == Some(expected) {
+            self.bytes.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// After an element of an object or an array: whether another element follows
+    fn separator(&mut self, close: u8) -> Result<bool, JsonError> {
+        if self.consume(b',') {
+            Ok(true)
+        } else if self.consume(close) {
+            Ok(false)
+        } else {
+            Err(JsonError::Malformed)
+        }
+    }
+
+    /// Move to the value of `key` in the object that comes next
+    fn find_key(&mut self, key: &[u8]) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(b'{') => self.bytes.next(),
+            Some(_) => return Err(JsonError::NotFound),
+            None => return Err(JsonError::Malformed),
+        };
+        if self.consume(b'}') {
+            return Err(JsonError::NotFound);
+        }
+
+        loop {
+            if !self.consume(b'"') {
+                return Err(JsonError::Malformed);
+            }
+            // Keys are compared unescaped
+            let mut len = 0;
+            let mut equal = true;
+            self.string(&mut |byte| {
+                equal = equal && key.get(len) == Some(&byte);
+                len += 1;
+            })?;
+            if !self.consume(b':') {
+                return Err(JsonError::Malformed);
+            }
+            if equal && len == key.len() {
+                return Ok(());
+            }
+
+            self.value(&mut |_| {})?;
+            if !self.separator(b'}')? {
+                return Err(JsonError::NotFound);
+            }
+        }
+    }
+
+    /// Move to the element at `index` in the array that comes next
+    fn find_index(&mut self, index: usize) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(b'[') => self.bytes.next(),
+            Some(_) => return Err(JsonError::NotFound),
+            None => return Err(JsonError::Malformed),
+        };
+        if self.consume(b']') {
+            return Err(JsonError::NotFound);
+        }
+
+        for _ in 0..index {
+            self.value(&mut |_| {})?;
+            if !self.separator(b']')? {
+                return Err(JsonError::NotFound);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass the value that comes next to `sink`
+    fn value(&mut self, sink: &mut dyn FnMut(u8)) -> Result<ValueType, JsonError> {
+        match self.peek() {
+            Some(b'"') => {
+                self.bytes.next();
+                self.string(sink)?;
+                Ok(ValueType::String)
+            }
+            Some(b'{') => self.container(sink, ValueType::Object),
+            Some(b'[') => self.container(sink, ValueType::Array),
+            Some(b't') => self.literal(b"true", sink, ValueType::Bool),
+            Some(b'f') => self.literal(b"false", sink, ValueType::Bool),
+            Some(b'n') => self.literal(b"null", sink, ValueType::Null),
+            Some(b'-') | Some(b'0'..=b'9') => {
+                while let Some(&byte) = self.bytes.peek() {
+                    if !matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E') {
+                        break;
+                    }
+                    sink(byte);
+                    self.bytes.next();
+                }
+                Ok(ValueType::Number)
+            }
+            _ => Err(JsonError::Malformed),
+        }
+    }
+
+    fn literal(
+        &mut self,
+        literal: &[u8],
+        sink: &mut dyn FnMut(u8),
+        value_type: ValueType,
+    ) -> Result<ValueType, JsonError> {
+        for &expected in literal {
+            if self.bytes.next() != Some(expected) {
+                return Err(JsonError::Malformed);
+            }
+            sink(expected);
+        }
+        Ok(value_type)
+    }
+
+    /// Pass an object or an array to `sink` as it is written, up to its closing bracket
+    fn container(
+        &mut self,
+        sink: &mut dyn FnMut(u8),
+        value_type: ValueType,
+    ) -> Result<ValueType, JsonError> {
+        let mut depth = 0;
+        let mut in_string = false;
+        let mut escaped = false;
+        loop {
+            let byte = self.bytes.next().ok_or(JsonError::Malformed)?;
+            sink(byte);
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if byte == b'\\' {
+                    escaped = true;
+                } else if byte == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+            match byte {
+                b'"' => in_string = true,
+                b'{' | b'[' => depth += 1,
+                b'}' | b']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(value_type);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Pass the rest of a string, after its opening quote, unescaped to `sink`
+    fn string(&mut self, sink: &mut dyn FnMut(u8)) -> Result<(), JsonError> {
+        loop {
+            match self.bytes.next() {
+                Some(b'"') => return Ok(()),
+                Some(b'\\') => {
+                    let escape = self.bytes.next();
+                    self.escape(escape, sink)?
+                }
+                // Control characters must be escaped
+                Some(byte) if byte >= 0x20 => sink(byte),
+                _ => return Err(JsonError::Malformed),
+            }
+        }
+    }
+
+    /// Unescape the escape sequence starting with `escape`, the byte after a backslash
+    fn escape(&mut self, escape: Option<u8>, sink: &mut dyn FnMut(u8)) -> Result<(), JsonError> {
+        let byte = match escape {
+            Some(b'u') => return self.unicode_escape(sink),
+            Some(b'"') => b'"',
+            Some(b'\\') => b'\\',
+            Some(b'/') => b'/',
+            Some(b'b') => 0x08,
+            Some(b'f') => 0x0c,
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            _ => return Err(JsonError::Malformed),
+        };
+        sink(byte);
+        Ok(())
+    }
+
+    /// Write the character of a `\uXXXX` escape sequence as UTF-8; characters outside the
+    /// basic plane come as two sequences (a surrogate pair)
+    ///
+    /// Surrogates that are not part of a pair are not characters: each of them is written as
+    /// U+FFFD, and what follows it is read as usual.
+    fn unicode_escape(&mut self, sink: &mut dyn FnMut(u8)) -> Result<(), JsonError> {
+        let mut code = self.hex4()?;
+        while (0xd800..0xdc00).contains(&code) {
+            if self.bytes.peek() != Some(&b'\\') {
+                write_char(char::REPLACEMENT_CHARACTER, sink);
+                return Ok(());
+            }
+            self.bytes.next();
+            let escape = self.bytes.next();
+            if escape != Some(b'u') {
+                write_char(char::REPLACEMENT_CHARACTER, sink);
+                return self.escape(escape, sink);
+            }
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                // The second sequence may start a pair of its own
+                write_char(char::REPLACEMENT_CHARACTER, sink);
+                code = low;
+                continue;
+            }
+            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+        }
+        // Lone low surrogates are the only codes left that are not characters
+        write_char(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER), sink);
+        Ok(())
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .bytes
+                .next()
+                .and_then(|byte| (byte as char).to_digit(16))
+                .ok_or(JsonError::Malformed)?;
+            code = code << 4 | digit;
+        }
+        Ok(code)
+    }
+}
+
+fn write_char(character: char, sink: &mut dyn FnMut(u8)) {
+    let mut utf8 = [0; 4];
+    for &byte in character.encode_utf8(&mut utf8).as_bytes() {
+        sink(byte);
+    }
+}
+
+/// Find the value at `path` in the JSON document `json` and pass it to `sink`; returns its type
+pub fn extract<I, F>(json: I, path: &[u8], mut sink: F) -> Result<ValueType, JsonError>
+where
+    I: IntoIterator<Item = u8>,
+    F: FnMut(u8),
+{
+    // Check the whole path first, so that a bad path is not reported as missing
+    if PathSteps::new(path).any(|step| step.is_err()) {
+        return Err(JsonError::InvalidPath);
+    }
+
+    let mut parser = Parser {
+        bytes: json.into_iter().peekable(),
+    };
+    for step in PathSteps::new(path) {
+        match step? {
+            Step::Key(key) => parser.find_key(key)?,
+            Step::Index(index) => parser.find_index(index)?,
+        }
+    }
+    parser.value(&mut sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Extract the value at `path`; returns its type with the bytes written to the sink
+    fn extract_value(json: &[u8], path: &[u8]) -> Result<(ValueType, [u8; 64], usize), JsonError> {
+        let mut value = [0; 64];
+        let mut len = 0;
+        let value_type = extract(json.iter().copied(), path, |byte| {
+            value[len] = byte;
+            len += 1;
+        })?;
+        Ok((value_type, value, len))
+    }
+
+    fn assert_value(json: &[u8], path: &[u8], value_type: ValueType, expected: &[u8]) {
+        let (found_type, value, len) = extract_value(json, path).unwrap();
+        assert_eq!(found_type, value_type);
+        assert_eq!(&value[..len], expected);
+    }
+
+    fn assert_error(json: &[u8], path: &[u8], error: JsonError) {
+        assert_eq!(extract_value(json, path).err(), Some(error));
+    }
+
+    #[test]
+    fn nested_paths() {
+        let json = br#"{"id": 3, "items": [{"name": "a"}, {"name": "b", "tags": [true, null, -1.5e3]}]}"#;
+        assert_value(json, b"id", ValueType::Number, b"3");
+        assert_value(json, b"items[1].name", ValueType::String, b"b");
+        assert_value(json, b"items[1].tags[0]", ValueType::Bool, b"true");
+        assert_value(json, b"items[1].tags[1]", ValueType::Null, b"null");
+        assert_value(json, b"items[1].tags[2]", ValueType::Number, b"-1.5e3");
+        assert_value(json, b"items[0]", ValueType::Object, br#"{"name": "a"}"#);
+        assert_value(br#" [[1, "]"], 2] "#, b"[0]", ValueType::Array, br#"[1, "]"]"#);
+        assert_value(br#"{"a": 1}"#, b"", ValueType::Object, br#"{"a": 1}"#);
+    }
+
+    #[test]
+    fn missing_keys() {
+        let json = br#"{"items": [{"name": "a"}], "count": 1}"#;
+        assert_error(json, b"name", JsonError::NotFound);
+        assert_error(json, b"items[0].id", JsonError::NotFound);
+        // A step into a value that is not an object or an array
+        assert_error(json, b"count.value", JsonError::NotFound);
+        assert_error(json, b"items.name", JsonError::NotFound);
+        assert_error(b"{}", b"name", JsonError::NotFound);
+    }
+
+    #[test]
+    fn index_past_the_end() {
+        let json = br#"{"items": [1, 2]}"#;
+        assert_value(json, b"items[1]", ValueType::Number, b"2");
+        assert_error(json, b"items[2]", JsonError::NotFound);
+        assert_error(br#"{"items": []}"#, b"items[0]", JsonError::NotFound);
+    }
+
+    #[test]
+    fn invalid_paths() {
+        let json = br#"{"items": [1]}"#;
+        assert_error(json, b"items[]", JsonError::InvalidPath);
+        assert_error(json, b"items[x]", JsonError::InvalidPath);
+        assert_error(json, b"items[0", JsonError::InvalidPath);
+        assert_error(json, b"items..name", JsonError::InvalidPath);
+        assert_error(json, b"items[0]name", JsonError::InvalidPath);
+    }
+
+    #[test]
+    fn malformed_documents() {
+        assert_error(b"", b"name", JsonError::Malformed);
+        assert_error(br#"{"name" "a"}"#, b"name", JsonError::Malformed);
+        assert_error(br#"{"id": 1 "name": "a"}"#, b"name", JsonError::Malformed);
+        assert_error(br#"{"name": "a"#, b"name", JsonError::Malformed);
+        assert_error(br#"{"name": tru}"#, b"name", JsonError::Malformed);
+        assert_error(b"{\"name\": \"a\nb\"}", b"name", JsonError::Malformed);
+        assert_error(br#"{"name": "\x"}"#, b"name", JsonError::Malformed);
+        assert_error(br#"{"name": "\u12"}"#, b"name", JsonError::Malformed);
+        assert_error(br#"{"items": [1, 2"#, b"items[0].id", JsonError::NotFound);
+        assert_error(br#"{"items": [1, 2"#, b"items[5]", JsonError::Malformed);
+    }
+
+    #[test]
+    fn escaped_keys() {
+        let json = br#"{"a\"b": 1, "caf\u00e9": 2, "tab\tkey": 3}"#;
+        assert_value(json, b"a\"b", ValueType::Number, b"1");
+        assert_value(json, "café".as_bytes(), ValueType::Number, b"2");
+        assert_value(json, b"tab\tkey", ValueType::Number, b"3");
+        assert_error(json, b"a\\\"b", JsonError::NotFound);
+    }
+
+    #[test]
+    fn escaped_strings() {
+        assert_value(br#""a\/b\\c\"d""#, b"", ValueType::String, br#"a/b\c"d"#);
+        assert_value(br#""\u0041\u00e9\u20ac""#, b"", ValueType::String, "Aé€".as_bytes());
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        assert_value(br#""\ud83d\ude00""#, b"", ValueType::String, "😀".as_bytes());
+        assert_value(br#""\uD83D\uDE00!""#, b"", ValueType::String, "😀!".as_bytes());
+    }
+
+    #[test]
+    fn lone_surrogates() {
+        // A high surrogate at the end of the string, or before another character
+        assert_value(br#""\ud83d""#, b"", ValueType::String, "\u{fffd}".as_bytes());
+        assert_value(br#""\ud83dx""#, b"", ValueType::String, "\u{fffd}x".as_bytes());
+        // A high surrogate before another escape sequence
+        assert_value(br#""\ud83d\n""#, b"", ValueType::String, "\u{fffd}\n".as_bytes());
+        assert_value(br#""\ud83d\u0041""#, b"", ValueType::String, "\u{fffd}A".as_bytes());
+        // Two high surrogates, the second one in a pair
+        assert_value(br#""\ud83d\ud83d\ude00""#, b"", ValueType::String, "\u{fffd}😀".as_bytes());
+        // A low surrogate alone
+        assert_value(br#""\ude00a""#, b"", ValueType::String, "\u{fffd}a".as_bytes());
+        assert_error(br#""\ud83d\q""#, b"", JsonError::Malformed);
+    }
+}
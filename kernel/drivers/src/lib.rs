@@ -1,5 +1,13 @@
 #![forbid(unsafe_code)]
 #![no_std]
 
+pub mod credential;
+pub mod http;
+pub mod json;
 pub mod network;
+pub mod network_driver;
+pub mod offline_queue;
+pub mod serial_channels;
+pub mod transport;
+pub mod virtual_http;
 pub mod led_matrix_text;
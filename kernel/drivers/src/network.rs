@@ -1,339 +1,1684 @@
-//! Network Driver
-//! Commands
-//!     0 -> SUCCESS
-//!     1 -> Network buffer
-//!     2 -> characters Networked
+//! Network stack over a serial proxy
 //!
-//! Allow
-//!     0 -> buffer to display
+//! `Network` sends HTTP requests to a proxy on the host (applications/network_adapter.py)
+//! over a `Transport` (see `crate::transport`) and implements the `Http` interface
+//! (see `crate::http`). Its users share it through `crate::virtual_http::MuxHttp`.
 //!
+//! Requests are sent as <METHOD> address\r\n<headers>\r\n\r\n<body> and the proxy answers
+//! with the response in a frame (see `FrameReceiver`). Each request is preceded by
+//! ID <request ID>\r\n (eight hex digits); the proxy performs the requests one at a time and
+//! echoes the ID in the accepted frame it sends before the response or error frame, so that
+//! the frames of a request that was canceled or timed out are skipped rather than taken as
+//! the answer to the next one. Chunked bodies
+//! (Transfer-Encoding: chunked) are decoded before they reach the client, and the `ETag` or
+//! `Last-Modified` header is given to it with the response, for conditional requests.
+//!
+//! The proxy may also push messages from the server at any time, in push frames. Once a
+//! `PushClient` is set, the driver keeps reading from the proxy between requests and
+//! hands it the pushed messages.
+//!
+//! Before sending requests, the driver checks that a proxy is there and speaks its protocol:
+//! it sends HELLO <version> <device ID> <capabilities>\r\n (two, sixteen and two hex digits)
+//! and the proxy answers with a hello frame carrying its version, its capabilities and the
+//! longest request it accepts. The proxy also sends a hello frame when it starts. Until a
+//! compatible proxy answered, requests fail right away with OFF and the driver sends the
+//! handshake again every few seconds. The link state is reported to a `LinkClient`.
+//!
+//! A client may ask for the response in segments (see `RequestWriter::set_segment_len`):
+//! the request is then preceded by PACE <segment length>\r\n (eight hex digits) and the
+//! proxy sends the payload of the response frame in segments of that length, each after a
+//! NEXT\r\n line from the driver. The proxy drops the rest of the response when another
+//! line comes instead, e.g. the next request.
+//!
+//! Once a `Credential` is set (see `crate::credential`), the requests to its origin get an
+//! Authorization: Bearer <token> header, added after the client wrote the request, so
+//! that the token never goes through the clients or the processes.
 
-// GET/POST address data_out (base64)\n
 use core::cell::Cell;
 
-use kernel::errorcode::into_statuscode;
-use kernel::grant::Grant;
-use kernel::hil::uart::{ReceiveClient, TransmitClient, UartData};
-use kernel::process::{Error, ProcessId};
-use kernel::processbuffer::{
-    ReadOnlyProcessBuffer, ReadWriteProcessBuffer, ReadableProcessBuffer, WriteableProcessBuffer,
+use kernel::hil::time::{Alarm, AlarmClient, ConvertTicks};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::credential::Credential;
+use crate::http::{
+    ContentType, Http, HttpClient, HttpError, HttpResponse, Method, NetworkError, PushClient,
+    RequestWriter, Validator, ValidatorKind,
 };
-use kernel::syscall::{CommandReturn, SyscallDriver};
-use kernel::utilities::cells::TakeCell;
-use kernel::{debug, ErrorCode};
+use crate::transport::{Transport, TransportClient};
+
+// Frames sent by the proxy:
+//     0xA5 0x5A | type (1 byte) | payload length (4 bytes, LE) | payload | CRC (2 bytes, LE)
+// The CRC-16/CCITT-FALSE covers the type, the length and the payload.
+// The driver reads the whole frame header at once, then the payload and the CRC
+// in reads of up to the size of the receive buffer.
+const FRAME_SYNC: [u8; 2] = [0xA5, 0x5A];
+// Type and payload length
+const FRAME_HEADER_LEN: usize = 5;
+const FRAME_CRC_LEN: usize = 2;
+/// The payload is the raw HTTP response (status line, headers and body)
+const FRAME_RESPONSE: u8 = 0x01;
+/// The request failed; the payload is one of the PROXY_ERROR_* codes
+const FRAME_ERROR: u8 = 0x02;
+/// The proxy read the request and is performing it; the payload is the request ID (4 bytes, LE)
+const FRAME_ACCEPTED: u8 = 0x03;
+const ACCEPTED_LEN: usize = 4;
+/// A message pushed by the server, sent outside of any request; the payload is the message
+const FRAME_PUSH: u8 = 0x04;
+/// Answer to the handshake, also sent when the proxy starts; the payload is
+///     version (1 byte) | capabilities (1 byte) | longest request accepted (4 bytes, LE)
+const FRAME_HELLO: u8 = 0x05;
+const HELLO_LEN: usize = 6;
+
+/// Version of the protocol spoken with the proxy
+pub const PROTOCOL_VERSION: u8 = 2;
+// Capabilities announced in the handshake, by the driver and by the proxy
+const CAPABILITY_PUSH: u8 = 0x01;
+const CAPABILITY_COMPRESSION: u8 = 0x02;
+const CAPABILITY_PACING: u8 = 0x04;
+/// Asks the proxy for the next segment of a paced response
+const PACE_NEXT: &[u8] = b"NEXT\r\n";
+/// Time the proxy has to answer the handshake
+const HANDSHAKE_TIMEOUT_MS: u32 = 1000;
+/// Time between handshakes while no proxy answers
+const HANDSHAKE_RETRY_MS: u32 = 5000;
+
+// Error vocabulary of the proxy; other codes are generic connection failures
+const PROXY_ERROR_CONNECTION_REFUSED: u8 = 0x01;
+const PROXY_ERROR_DNS_FAILURE: u8 = 0x02;
+const PROXY_ERROR_TIMEOUT: u8 = 0x03;
+const PROXY_ERROR_INVALID_REQUEST: u8 = 0x04;
+
+/// Decode the payload of an error frame
+fn proxy_error(code: u8) -> NetworkError {
+    match code {
+        PROXY_ERROR_CONNECTION_REFUSED => NetworkError::ConnectionRefused,
+        PROXY_ERROR_DNS_FAILURE => NetworkError::DnsFailure,
+        PROXY_ERROR_TIMEOUT => NetworkError::Timeout,
+        PROXY_ERROR_INVALID_REQUEST => NetworkError::InvalidRequest,
+        _ => NetworkError::ConnectionFailed,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FrameState {
+    Sync(usize),
+    Type,
+    Length(usize),
+    Payload,
+    Crc(usize),
+}
+
+enum FrameEvent {
+    None,
+    Payload(u8),
+    // The frame is complete; whether its CRC matches
+    End(bool),
+}
+
+/// Splits the bytes received from the proxy into frames
+struct FrameReceiver {
+    state: Cell<FrameState>,
+    frame_type: Cell<u8>,
+    len: Cell<u32>,
+    remaining: Cell<u32>,
+    crc: Cell<u16>,
+    received_crc: Cell<u16>,
+}
+
+impl FrameReceiver {
+    fn new() -> FrameReceiver {
+        FrameReceiver {
+            state: Cell::new(FrameState::Sync(0)),
+            frame_type: Cell::new(0),
+            len: Cell::new(0),
+            remaining: Cell::new(0),
+            crc: Cell::new(0),
+            received_crc: Cell::new(0),
+        }
+    }
+
+    /// Wait for the start of a new frame
+    fn reset(&self) {
+        self.state.set(FrameState::Sync(0));
+    }
+
+    fn frame_type(&self) -> u8 {
+        self.frame_type.get()
+    }
+
+    /// Payload length of the current frame
+    fn len(&self) -> usize {
+        self.len.get() as usize
+    }
+
+    /// Number of payload bytes of the current frame still to be received, while receiving them
+    fn remaining(&self) -> Option<usize> {
+        match self.state.get() {
+            FrameState::Payload => Some(self.remaining.get() as usize),
+            _ => None,
+        }
+    }
+
+    /// Number of payload bytes of the current frame received so far
+    fn received(&self) -> usize {
+        match self.state.get() {
+            FrameState::Payload => (self.len.get() - self.remaining.get()) as usize,
+            FrameState::Crc(_) => self.len.get() as usize,
+            _ => 0,
+        }
+    }
+
+    /// Number of bytes to read next without reading past the end of the frame:
+    /// the rest of the frame header, then the payload and the CRC
+    fn expected_len(&self) -> usize {
+        match self.state.get() {
+            FrameState::Sync(i) => FRAME_SYNC.len() - i + FRAME_HEADER_LEN,
+            FrameState::Type => FRAME_HEADER_LEN,
+            FrameState::Length(i) => FRAME_HEADER_LEN - 1 - i,
+            FrameState::Payload => self.remaining.get() as usize + FRAME_CRC_LEN,
+            FrameState::Crc(i) => FRAME_CRC_LEN - i,
+        }
+    }
+
+    fn receive(&self, byte: u8) -> FrameEvent {
+        match self.state.get() {
+            FrameState::Sync(i) => {
+                if byte == FRAME_SYNC[i] {
+                    if i + 1 == FRAME_SYNC.len() {
+                        self.crc.set(0xFFFF);
+                        self.state.set(FrameState::Type);
+                    } else {
+                        self.state.set(FrameState::Sync(i + 1));
+                    }
+                } else if byte == FRAME_SYNC[0] {
+                    self.state.set(FrameState::Sync(1));
+                } else {
+                    self.state.set(FrameState::Sync(0));
+                }
+                FrameEvent::None
+            }
+            FrameState::Type => {
+                self.update_crc(byte);
+                self.frame_type.set(byte);
+                self.len.set(0);
+                self.state.set(FrameState::Length(0));
+                FrameEvent::None
+            }
+            FrameState::Length(i) => {
+                self.update_crc(byte);
+                self.len.set(self.len.get() | ((byte as u32) << (8 * i)));
+                if i + 1 < 4 {
+                    self.state.set(FrameState::Length(i + 1));
+                } else {
+                    self.remaining.set(self.len.get());
+                    self.received_crc.set(0);
+                    self.state.set(if self.len.get() > 0 { FrameState::Payload } else { FrameState::Crc(0) });
+                }
+                FrameEvent::None
+            }
+            FrameState::Payload => {
+                self.update_crc(byte);
+                self.remaining.set(self.remaining.get() - 1);
+                if self.remaining.get() == 0 {
+                    self.state.set(FrameState::Crc(0));
+                }
+                FrameEvent::Payload(byte)
+            }
+            FrameState::Crc(i) => {
+                self.received_crc.set(self.received_crc.get() | ((byte as u16) << (8 * i)));
+                if i == 0 {
+                    self.state.set(FrameState::Crc(1));
+                    FrameEvent::None
+                } else {
+                    self.state.set(FrameState::Sync(0));
+                    FrameEvent::End(self.received_crc.get() == self.crc.get())
+                }
+            }
+        }
+    }
+
+    fn update_crc(&self, byte: u8) {
+        let mut crc = self.crc.get() ^ ((byte as u16) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        self.crc.set(crc);
+    }
+}
+
+/// Longest header line kept for inspecting its value; longer lines are still copied to the process
+const HEADER_LINE_LEN: usize = 80;
+
+#[derive(Copy, Clone, PartialEq)]
+enum ResponseState {
+    StatusLine,
+    Headers,
+    // The body lasts until the end of the frame
+    Body,
+    // Chunked body: the size line of a chunk, its extensions, its data and the line break after it
+    ChunkSize,
+    ChunkExtension,
+    ChunkData(usize),
+    ChunkDataEnd,
+    // Trailer headers after the last chunk
+    Trailers,
+    Done,
+    // The response is not valid HTTP; the rest of it is dropped
+    Invalid,
+}
+
+enum ResponseEvent {
+    None,
+    // A byte of the status line or of the headers, with its index
+    Header(usize, u8),
+    // A byte of the decoded body, with its index
+    Body(usize, u8),
+}
+
+/// Splits the response of the proxy into the status line, the headers and the body,
+/// decoding chunked bodies (Transfer-Encoding: chunked) as they are received
+struct ResponseParser {
+    state: Cell<ResponseState>,
+    status_code: Cell<Option<usize>>,
+    chunked: Cell<bool>,
+    validator: Cell<Option<Validator>>,
+    // Current line of the headers; for chunked bodies, the number of digits of the chunk size
+    // or the length of the current trailer line
+    line: MapCell<[u8; HEADER_LINE_LEN]>,
+    line_len: Cell<usize>,
+    line_start: Cell<usize>,
+    headers_received: Cell<usize>,
+    headers_len: Cell<Option<usize>>,
+    chunk_size: Cell<usize>,
+    body_len: Cell<usize>,
+}
+
+impl ResponseParser {
+    fn new() -> ResponseParser {
+        ResponseParser {
+            state: Cell::new(ResponseState::StatusLine),
+            status_code: Cell::new(None),
+            chunked: Cell::new(false),
+            validator: Cell::new(None),
+            line: MapCell::new([0; HEADER_LINE_LEN]),
+            line_len: Cell::new(0),
+            line_start: Cell::new(0),
+            headers_received: Cell::new(0),
+            headers_len: Cell::new(None),
+            chunk_size: Cell::new(0),
+            body_len: Cell::new(0),
+        }
+    }
+
+    /// Wait for the start of a new response
+    fn reset(&self) {
+        self.state.set(ResponseState::StatusLine);
+        self.status_code.set(None);
+        self.chunked.set(false);
+        self.validator.set(None);
+        self.line_len.set(0);
+        self.line_start.set(0);
+        self.headers_received.set(0);
+        self.headers_len.set(None);
+        self.chunk_size.set(0);
+        self.body_len.set(0);
+    }
+
+    fn status_code(&self) -> Option<usize> {
+        self.status_code.get()
+    }
+
+    /// Length of the status line and the headers, without the empty line that ends them
+    fn headers_len(&self) -> Option<usize> {
+        self.headers_len.get()
+    }
+
+    /// The `ETag` of the response or, without one, its `Last-Modified` date
+    fn validator(&self) -> Option<Validator> {
+        self.validator.get()
+    }
+
+    /// Length of the decoded body received so far
+    fn body_len(&self) -> usize {
+        self.body_len.get()
+    }
+
+    /// Whether the whole response was received, once the frame that carries it ended
+    fn is_complete(&self) -> bool {
+        match self.state.get() {
+            ResponseState::Body | ResponseState::Done => true,
+            // Responses to HEAD requests and 204 or 304 responses may announce
+            // a chunked body without having one
+            ResponseState::ChunkSize => self.body_len.get() == 0 && self.line_len.get() == 0,
+            _ => false,
+        }
+    }
+
+    fn receive(&self, byte: u8) -> ResponseEvent {
+        match self.state.get() {
+            ResponseState::StatusLine | ResponseState::Headers => {
+                let index = self.headers_received.get();
+                self.headers_received.set(index + 1);
+                if byte == '\n' as u8 {
+                    self.end_header_line();
+                } else if byte != '\r' as u8 {
+                    let line_len = self.line_len.get();
+                    if line_len < HEADER_LINE_LEN {
+                        self.line.map(|line| line[line_len] = byte);
+                    }
+                    self.line_len.set(line_len + 1);
+                }
+                ResponseEvent::Header(index, byte)
+            }
+            ResponseState::Body => self.body_byte(byte),
+            ResponseState::ChunkSize => {
+                match (byte as char).to_digit(16) {
+                    Some(digit) => {
+                        match self.chunk_size.get().checked_mul(16) {
+                            Some(chunk_size) => {
+                                self.chunk_size.set(chunk_size + digit as usize);
+                                self.line_len.set(self.line_len.get() + 1);
+                            }
+                            None => self.state.set(ResponseState::Invalid),
+                        }
+                    }
+                    None if byte == ';' as u8 || byte == ' ' as u8 || byte == '\t' as u8 => {
+                        self.state.set(ResponseState::ChunkExtension);
+                    }
+                    None if byte == '\r' as u8 => {}
+                    None if byte == '\n' as u8 => self.end_chunk_size(),
+                    None => self.state.set(ResponseState::Invalid),
+                }
+                ResponseEvent::None
+            }
+            ResponseState::ChunkExtension => {
+                // Chunk extensions are ignored
+                if byte == '\n' as u8 {
+                    self.end_chunk_size();
+                }
+                ResponseEvent::None
+            }
+            ResponseState::ChunkData(remaining) => {
+                self.state.set(if remaining > 1 {
+                    ResponseState::ChunkData(remaining - 1)
+                } else {
+                    ResponseState::ChunkDataEnd
+                });
+                self.body_byte(byte)
+            }
+            ResponseState::ChunkDataEnd => {
+                if byte == '\n' as u8 {
+                    self.chunk_size.set(0);
+                    self.line_len.set(0);
+                    self.state.set(ResponseState::ChunkSize);
+                } else if byte != '\r' as u8 {
+                    self.state.set(ResponseState::Invalid);
+                }
+                ResponseEvent::None
+            }
+            ResponseState::Trailers => {
+                // Trailer headers are dropped; an empty line ends them
+                if byte == '\n' as u8 {
+                    if self.line_len.get() == 0 {
+                        self.state.set(ResponseState::Done);
+                    }
+                    self.line_len.set(0);
+                } else if byte != '\r' as u8 {
+                    self.line_len.set(self.line_len.get() + 1);
+                }
+                ResponseEvent::None
+            }
+            ResponseState::Done | ResponseState::Invalid => ResponseEvent::None,
+        }
+    }
+
+    fn body_byte(&self, byte: u8) -> ResponseEvent {
+        let index = self.body_len.get();
+        self.body_len.set(index + 1);
+        ResponseEvent::Body(index, byte)
+    }
+
+    fn end_header_line(&self) {
+        let line_len = self.line_len.get();
+        let state = self.state.get();
+        self.line.map(|line| {
+            let line = &line[..core::cmp::min(line_len, HEADER_LINE_LEN)];
+            if state == ResponseState::StatusLine {
+                self.status_code.set(parse_status_code(line));
+                self.state.set(if self.status_code.get().is_some() {
+                    ResponseState::Headers
+                } else {
+                    ResponseState::Invalid
+                });
+            } else if line_len == 0 {
+                // The empty line ends the headers
+                self.headers_len.set(Some(self.line_start.get()));
+                self.line_len.set(0);
+                self.state.set(if self.chunked.get() { ResponseState::ChunkSize } else { ResponseState::Body });
+            } else if let Some(value) = header_value(line, "Transfer-Encoding") {
+                // Chunked is the last of the codings, if the body is chunked
+                let chunked = "chunked".as_bytes();
+                self.chunked.set(
+                    value.len() >= chunked.len() &&
+                    value[value.len() - chunked.len()..].eq_ignore_ascii_case(chunked),
+                );
+            } else if line_len > HEADER_LINE_LEN {
+                // The value was cut off; it cannot be used as a validator
+            } else if let Some(value) = header_value(line, "ETag") {
+                if let Some(validator) = Validator::new(ValidatorKind::ETag, value) {
+                    self.validator.set(Some(validator));
+                }
+            } else if let Some(value) = header_value(line, "Last-Modified") {
+                // The entity tag is the more precise validator
+                let has_etag = matches!(self.validator.get(), Some(Validator { kind: ValidatorKind::ETag, .. }));
+                if let Some(validator) = Validator::new(ValidatorKind::LastModified, value).filter(|_| !has_etag) {
+                    self.validator.set(Some(validator));
+                }
+            }
+        });
+        self.line_len.set(0);
+        self.line_start.set(self.headers_received.get());
+    }
+
+    fn end_chunk_size(&self) {
+        if self.line_len.get() == 0 {
+            // No chunk size digits
+            self.state.set(ResponseState::Invalid);
+        } else if self.chunk_size.get() == 0 {
+            // The last chunk
+            self.line_len.set(0);
+            self.state.set(ResponseState::Trailers);
+        } else {
+            self.state.set(ResponseState::ChunkData(self.chunk_size.get()));
+        }
+    }
+}
 
-pub const DRIVER_NUM: usize = 0xa0001;
+/// Time the proxy has to answer a request, unless the request sets its own timeout
+const DEFAULT_TIMEOUT_MS: u32 = 10000;
+
+/// What the proxy announced in the handshake
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ProxyInfo {
+    pub version: u8,
+    /// Longest request the proxy accepts
+    pub max_request_len: usize,
+    /// The proxy forwards the messages pushed by the server
+    pub push: bool,
+    /// The proxy can compress the responses; the driver does not ask for it
+    pub compression: bool,
+    /// The proxy can send responses in segments
+    pub pacing: bool,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LinkState {
+    /// No proxy answered the handshake
+    Down,
+    Handshaking,
+    Up(ProxyInfo),
+    /// The proxy speaks another version of the protocol
+    Incompatible(u8),
+}
+
+/// Link between the driver and the proxy
+pub trait ProxyLink<'a> {
+    fn set_link_client(&self, client: &'a dyn LinkClient);
+
+    fn link_state(&self) -> LinkState;
+
+    /// Send the handshake again; `LinkClient::link_changed` reports its result
+    ///
+    /// Fails with BUSY while a request is ongoing.
+    fn handshake(&self) -> Result<(), ErrorCode>;
+}
+
+pub trait LinkClient {
+    fn link_changed(&self, state: LinkState);
+}
 
 #[derive(Copy, Clone)]
 enum NetworkState {
     Idle,
-    Requesting(ProcessId),
+    // Waiting for the answer of the proxy to the handshake, or for the transport to give back
+    // the buffer once it answered
+    Handshaking,
+    Requesting,
+    // The request ended (e.g. it timed out or was canceled) while the transport still had the
+    // buffer, or it was canceled and ends from the alarm
+    Ending(Result<HttpResponse, HttpError>),
 }
 
-#[derive(Default)]
-pub struct AppStorage {
-    address: ReadOnlyProcessBuffer,
-    data_out: ReadOnlyProcessBuffer,
-    data_in: ReadWriteProcessBuffer,
+// Kind of a run of decoded bytes
+#[derive(Copy, Clone, PartialEq)]
+enum Run {
+    Headers,
+    Body,
+    Push,
 }
 
-pub struct Network<'a> {
-    grant_access: Grant<AppStorage, 1>,
-    uart: &'a dyn UartData<'a>,
+pub struct Network<'a, A: Alarm<'a>> {
+    transport: &'a dyn Transport<'a>,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn HttpClient>,
+    push_client: OptionalCell<&'a dyn PushClient>,
+    link_client: OptionalCell<&'a dyn LinkClient>,
+    credential: OptionalCell<&'a dyn Credential>,
     state: Cell<NetworkState>,
+    link: Cell<LinkState>,
+    // Sent to the proxy in the handshake; 0 if the board has no ID
+    device_id: Cell<u64>,
+    // Buffer of the requests
     buffer: TakeCell<'static, [u8]>,
-    response_curr_idx: Cell<usize>,
+    // Buffer of the bytes received from the proxy
+    rx_buffer: TakeCell<'static, [u8]>,
+    receiving: Cell<bool>,
+    frame: FrameReceiver,
+    response: ResponseParser,
+    // ID of the latest request
+    request_id: Cell<u32>,
+    // Whether the proxy accepted the ongoing request; its response and error frames are
+    // skipped until then
+    accepted: Cell<bool>,
+    // Payload of the accepted frame being received
+    accepted_id: Cell<u32>,
+    accepted_len: Cell<usize>,
+    // Bytes of an abandoned paced response still to come, skipped before looking for the
+    // next frame
+    skip: Cell<usize>,
+    // Payload of the error frame being received
+    proxy_error: Cell<u8>,
+    // Length of the pushed message received so far
+    push_len: Cell<usize>,
+    // Payload of the hello frame being received
+    hello: Cell<[u8; HELLO_LEN]>,
+    hello_len: Cell<usize>,
+    // Timeout of the ongoing request
+    timeout_ms: Cell<u32>,
+    // Segment length of the paced response, 0 if the response comes at once
+    segment_len: Cell<usize>,
+    // The client holds the next segment
+    held: Cell<bool>,
+    // The next segment is to be asked for once the transport gives back the buffer
+    next_pending: Cell<bool>,
 }
 
-impl<'a> Network<'a> {
+impl<'a, A: Alarm<'a>> Network<'a, A> {
     pub fn new(
-        grant_access: Grant<AppStorage, 1>,
-        uart: &'a dyn UartData<'a>,
+        transport: &'a dyn Transport<'a>,
+        alarm: &'a A,
         buffer: &'static mut [u8],
-    ) -> Network<'a> {
+        rx_buffer: &'static mut [u8],
+    ) -> Network<'a, A> {
         Network {
-            grant_access,
-            uart: uart,
+            transport,
+            alarm,
+            client: OptionalCell::empty(),
+            push_client: OptionalCell::empty(),
+            link_client: OptionalCell::empty(),
+            credential: OptionalCell::empty(),
             state: Cell::new(NetworkState::Idle),
+            link: Cell::new(LinkState::Down),
+            device_id: Cell::new(0),
             buffer: TakeCell::new(buffer),
-            response_curr_idx: Cell::new(0),
+            rx_buffer: TakeCell::new(rx_buffer),
+            receiving: Cell::new(false),
+            frame: FrameReceiver::new(),
+            response: ResponseParser::new(),
+            request_id: Cell::new(0),
+            accepted: Cell::new(false),
+            accepted_id: Cell::new(0),
+            accepted_len: Cell::new(0),
+            skip: Cell::new(0),
+            proxy_error: Cell::new(0),
+            push_len: Cell::new(0),
+            hello: Cell::new([0; HELLO_LEN]),
+            hello_len: Cell::new(0),
+            timeout_ms: Cell::new(DEFAULT_TIMEOUT_MS),
+            segment_len: Cell::new(0),
+            held: Cell::new(false),
+            next_pending: Cell::new(false),
         }
     }
-}
 
-impl<'a> SyscallDriver for Network<'a> {
-    fn command(
-        &self,
-        command_num: usize,
-        _r2: usize,
-        _r3: usize,
-        process_id: ProcessId,
-    ) -> CommandReturn {
-        match command_num {
-            0 => CommandReturn::success(),
-            // send request
-            1 => {
-                if let NetworkState::Idle = self.state.get() {
-                    self.response_curr_idx.set(0);
-                    let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
-                        // Result<Result<(), ErrorCode>, Error>
-                        let res = app_storage.address.enter(|address| {
-                            let buffer = self.buffer.take();
-                            if let Some(buffer) = buffer {
-                                // buf[index].get() -> u8
-                                if 5 + address.len() <= buffer.len() {
-
-                                    address.copy_to_slice(&mut buffer[5..5 + address.len()]);
-
-                                    if app_storage.data_out.len() > 0 {
-                                        // POST
-                                        app_storage.data_out.enter(move |data_out| {
-
-                                            let len1 = 5 + address.len();
-                                            let len2 = len1 + 57 + data_out.len();
-
-                                            if len2 + 2 <= buffer.len() {
-
-                                                &buffer[0..5].copy_from_slice("POST ".as_bytes());
-                                                &buffer[len1..len1 + 57].copy_from_slice("\r\nContent-Type: application/json\r\nContent-Length: 000\r\n\r\n".as_bytes());
-                                                buffer[len1 + 50] = (data_out.len() / 100) as u8 + '0' as u8;
-                                                buffer[len1 + 51] = (data_out.len() / 10 % 10) as u8 + '0' as u8;
-                                                buffer[len1 + 52] = (data_out.len() % 10) as u8 + '0' as u8;
-                                                data_out.copy_to_slice(&mut buffer[len1 + 57..len2]);
-                                                &buffer[len2..len2 + 2].copy_from_slice("\r\n".as_bytes());
-
-                                                if let Err((error, buffer)) = self.uart.transmit_buffer(buffer, len2 + 2) {
-                                                    self.buffer.replace(buffer);
-                                                    Err(error)
-                                                }
-                                                else {
-                                                    self.state.set(NetworkState::Requesting(process_id));
-                                                    Ok(())
-                                                }
-                                            } else {
-                                                Err(ErrorCode::INVAL)
-                                            }
-                                        })
-                                        .map_err(|err| err.into())
-                                        .and_then(|x| x)
-                                    } else {
-                                        // GET
-                                        &buffer[0..5].copy_from_slice("GET  ".as_bytes());
-                                        &buffer[5 + address.len()..9 + address.len()].copy_from_slice("\r\n\r\n".as_bytes());
-
-                                        if let Err((error, buffer)) = self.uart.transmit_buffer(buffer, 5 + address.len() + 5) {
-                                            self.buffer.replace(buffer);
-                                            Err(error)
-                                        }
-                                        else {
-                                            self.state.set(NetworkState::Requesting(process_id));
-                                            Ok(())
-                                        }
-                                    }
-                                } else {
-                                    Err(ErrorCode::SIZE)
-                                }
-                            } else {
-                                Err(ErrorCode::NOMEM)
-                            }
-                        });
-                        match res {
-                            Ok(Ok(())) => Ok(()),
-                            Ok(Err(err)) => Err(err),
-                            Err(err) => Err(err.into()),
-                        }
-                    });
-                    match res {
-                        Ok(Ok(())) => CommandReturn::success(),
-                        Ok(Err(err)) => CommandReturn::failure(err),
-                        Err(err) => CommandReturn::failure(err.into()),
+    /// Set the ID sent to the proxy in the handshake
+    pub fn set_device_id(&self, device_id: u64) {
+        self.device_id.set(device_id);
+    }
+
+    /// Authorize the requests to the origin of `credential`
+    pub fn set_credential(&self, credential: &'a dyn Credential) {
+        self.credential.set(credential);
+    }
+
+    /// Add the Authorization header to the request of `len` bytes in `buffer`, if it goes to
+    /// the origin of the credential; returns the new length
+    fn authorize(&self, buffer: &mut [u8], len: usize) -> Result<usize, ErrorCode> {
+        let mut res = Ok(len);
+        self.credential.map(|credential| {
+            credential.with_credential(&mut |origin, token| {
+                res = write_authorization(&mut buffer[..], len, origin, token);
+            })
+        });
+        res
+    }
+
+    /// Start listening for the messages pushed by the server
+    pub fn set_push_client(&self, push_client: &'a dyn PushClient) {
+        self.push_client.set(push_client);
+        self.listen();
+    }
+
+    /// Return to idle and report the end of the request; the buffer must be back
+    fn finish(&self, result: Result<HttpResponse, HttpError>) {
+        let _ = self.alarm.disarm();
+        self.drop_response();
+        self.held.set(false);
+        self.next_pending.set(false);
+        self.state.set(NetworkState::Idle);
+        self.schedule_handshake();
+        self.client.map(|client| client.request_done(result));
+    }
+
+    /// Drop the rest of the paced response of the request that ended, if it is being received
+    ///
+    /// The proxy drops the rest of a paced response once the next line arrives, so only the
+    /// rest of its current segment still comes; it is skipped and the driver looks for the
+    /// next frame. The rest of a response that comes at once is skipped as it arrives, like
+    /// any response frame that no request waits for.
+    fn drop_response(&self) {
+        let segment_len = self.segment_len.get();
+        if self.frame.frame_type() != FRAME_RESPONSE {
+            return;
+        }
+        let remaining = match self.frame.remaining() {
+            Some(remaining) if segment_len > 0 && self.accepted.get() => remaining,
+            _ => return,
+        };
+        let received = self.frame.received();
+        // The first segment follows the frame header, the others come once asked for
+        let left = match received % segment_len {
+            0 if received > 0 && (self.held.get() || self.next_pending.get()) => 0,
+            0 => segment_len,
+            in_segment => segment_len - in_segment,
+        };
+        if remaining > left {
+            self.skip.set(left);
+            self.frame.reset();
+        }
+    }
+
+    /// Take the accepted frame of the proxy: the ongoing request is accepted if the frame
+    /// carries its ID
+    fn receive_accepted(&self, valid: bool) {
+        let accepted_len = self.accepted_len.get();
+        self.accepted_len.set(0);
+        if valid
+            && accepted_len == ACCEPTED_LEN
+            && self.accepted_id.get() == self.request_id.get()
+            && matches!(self.state.get(), NetworkState::Requesting)
+        {
+            self.accepted.set(true);
+        }
+    }
+
+    /// Set the link state, ending the handshake, and report it if it changed
+    fn update_link(&self, state: LinkState) {
+        let changed = self.link.get() != state;
+        self.link.set(state);
+        self.end_handshake();
+        if changed {
+            self.link_client.map(|link_client| link_client.link_changed(state));
+        }
+    }
+
+    /// Return to idle once the handshake got its answer, or none in time, and the transport
+    /// gave back the buffer
+    fn end_handshake(&self) {
+        if matches!(self.state.get(), NetworkState::Handshaking)
+            && self.link.get() != LinkState::Handshaking
+            && self.buffer.is_some()
+        {
+            let _ = self.alarm.disarm();
+            self.state.set(NetworkState::Idle);
+            self.schedule_handshake();
+        }
+    }
+
+    /// Send the handshake again later, while no proxy answers
+    fn schedule_handshake(&self) {
+        if matches!(self.state.get(), NetworkState::Idle) && self.link.get() == LinkState::Down {
+            self.alarm.set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(HANDSHAKE_RETRY_MS));
+        }
+    }
+
+    /// Take the hello frame of the proxy, received during the handshake or when it started
+    fn receive_hello(&self, valid: bool) {
+        let hello_len = self.hello_len.get();
+        self.hello_len.set(0);
+        if !valid || hello_len != HELLO_LEN {
+            // Corrupted on the serial link; the handshake times out and is sent again
+            return;
+        }
+
+        let hello = self.hello.get();
+        let state = if hello[0] == PROTOCOL_VERSION {
+            LinkState::Up(ProxyInfo {
+                version: hello[0],
+                max_request_len: u32::from_le_bytes([hello[2], hello[3], hello[4], hello[5]]) as usize,
+                push: hello[1] & CAPABILITY_PUSH != 0,
+                compression: hello[1] & CAPABILITY_COMPRESSION != 0,
+                pacing: hello[1] & CAPABILITY_PACING != 0,
+            })
+        } else {
+            LinkState::Incompatible(hello[0])
+        };
+        self.update_link(state);
+    }
+
+    /// End the ongoing request, or let the transport end it once it gives back the buffer
+    ///
+    /// The rest of the response is dropped as it arrives.
+    fn end_request(&self, result: Result<HttpResponse, HttpError>) {
+        if self.buffer.is_some() {
+            self.finish(result);
+        } else {
+            self.state.set(NetworkState::Ending(result));
+        }
+    }
+
+    /// Read from the proxy while a request or the handshake waits for an answer, or a push
+    /// client waits for messages
+    fn listen(&self) {
+        let requesting = matches!(self.state.get(), NetworkState::Requesting);
+        let handshaking = matches!(self.state.get(), NetworkState::Handshaking);
+        if self.receiving.get() || !(requesting || handshaking || self.push_client.is_some()) {
+            return;
+        }
+        if let Some(rx_buffer) = self.rx_buffer.take() {
+            let mut len = core::cmp::min(self.frame.expected_len(), rx_buffer.len());
+            if self.skip.get() > 0 {
+                len = core::cmp::min(self.skip.get(), rx_buffer.len());
+            } else if let Some(segment_left) = self.segment_left() {
+                // The proxy sends the next segment only once asked for it
+                len = core::cmp::min(len, segment_left);
+            }
+            match self.transport.receive(rx_buffer, len) {
+                Ok(()) => self.receiving.set(true),
+                Err((error, rx_buffer)) => {
+                    self.rx_buffer.replace(rx_buffer);
+                    if requesting {
+                        self.end_request(Err(HttpError::Kernel(error)));
+                    } else if handshaking && self.link.get() == LinkState::Handshaking {
+                        self.update_link(LinkState::Down);
                     }
-                } else {
-                    CommandReturn::failure(ErrorCode::BUSY)
                 }
             }
-            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
         }
     }
 
-    fn allow_readonly(
-        &self,
-        process_id: ProcessId,
-        allow_num: usize,
-        mut buffer: ReadOnlyProcessBuffer,
-    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
-        match allow_num {
-            // address
-            0 => {
-                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
-                    core::mem::swap(&mut app_storage.address, &mut buffer);
-                });
-                match res {
-                    Ok(()) => Ok(buffer),
-                    Err(err) => Err((buffer, err.into())),
-                }
+    /// Bytes left in the current segment of a paced response, unless the response ends first
+    fn segment_left(&self) -> Option<usize> {
+        let segment_len = self.segment_len.get();
+        if segment_len == 0 || !self.accepted.get() || self.frame.frame_type() != FRAME_RESPONSE {
+            return None;
+        }
+        let remaining = self.frame.remaining()?;
+        let left = segment_len - self.frame.received() % segment_len;
+        if remaining > left {
+            Some(left)
+        } else {
+            None
+        }
+    }
+
+    /// At the end of a segment of a paced response, ask the proxy for the next one unless the
+    /// client holds it
+    fn end_segment(&self) {
+        let segment_len = self.segment_len.get();
+        let received = self.frame.received();
+        if segment_len == 0
+            || self.held.get()
+            || !self.accepted.get()
+            || !matches!(self.state.get(), NetworkState::Requesting)
+            || self.frame.frame_type() != FRAME_RESPONSE
+            || self.frame.remaining().is_none()
+            || received == 0
+            || received % segment_len != 0
+        {
+            return;
+        }
+
+        if self.client.map_or(true, |client| client.segment_done()) {
+            self.send_next();
+        } else {
+            // The proxy waits as long as the client does
+            self.held.set(true);
+            let _ = self.alarm.disarm();
+        }
+    }
+
+    /// Ask the proxy for the next segment of the paced response
+    fn send_next(&self) {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => {
+                self.next_pending.set(true);
+                return;
             }
-            // data_out
-            1 => {
-                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
-                    core::mem::swap(&mut app_storage.data_out, &mut buffer);
-                });
-                match res {
-                    Ok(()) => Ok(buffer),
-                    Err(err) => Err((buffer, err.into())),
-                }
+        };
+        buffer[..PACE_NEXT.len()].copy_from_slice(PACE_NEXT);
+        match self.transport.transmit(buffer, PACE_NEXT.len()) {
+            Ok(()) => {
+                self.alarm.set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(self.timeout_ms.get()));
+            }
+            Err((error, buffer)) => {
+                self.buffer.replace(buffer);
+                self.end_request(Err(HttpError::Kernel(error)));
             }
-            _ => Err((buffer, ErrorCode::NOSUPPORT)),
         }
     }
 
-    fn allow_readwrite(
-        &self,
-        process_id: ProcessId,
-        allow_num: usize,
-        mut buffer: ReadWriteProcessBuffer,
-    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
-        match allow_num {
-            // data_in
-            0 => {
-                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
-                    core::mem::swap(&mut app_storage.data_in, &mut buffer);
-                });
-                match res {
-                    Ok(()) => Ok(buffer),
-                    Err(err) => Err((buffer, err.into())),
+    /// Drop the pushed message being received, if any
+    fn drop_push(&self) {
+        if self.push_len.get() > 0 {
+            self.push_len.set(0);
+            self.push_client
+                .map(|push_client| push_client.push_done(Err(NetworkError::MalformedResponse)));
+        }
+    }
+
+    /// Result of the request once its response frame ended
+    fn response_result(&self, valid: bool) -> Result<HttpResponse, HttpError> {
+        if !valid {
+            // The frame was corrupted on the serial link
+            return Err(HttpError::Network(NetworkError::MalformedResponse));
+        }
+        match (self.response.status_code(), self.response.headers_len()) {
+            (Some(status_code), Some(headers_len)) if self.response.is_complete() => Ok(HttpResponse {
+                status_code,
+                headers_len,
+                body_len: self.response.body_len(),
+                validator: self.response.validator(),
+            }),
+            // No status line, unterminated headers or a malformed chunked body
+            _ => Err(HttpError::Network(NetworkError::MalformedResponse)),
+        }
+    }
+
+    /// Result of the request once the error frame of the proxy ended
+    fn error_result(&self, valid: bool) -> Result<HttpResponse, HttpError> {
+        if valid {
+            Err(HttpError::Network(proxy_error(self.proxy_error.get())))
+        } else {
+            Err(HttpError::Network(NetworkError::MalformedResponse))
+        }
+    }
+
+    /// Handle the bytes received from the proxy; returns whether the frame that ends
+    /// the request ended and whether it was valid
+    ///
+    /// The decoded bytes of the response and the bytes of pushed messages are gathered at
+    /// the beginning of `buffer`, over the bytes already handled, and given to the clients
+    /// in runs of header, body or pushed bytes. Response and error frames are skipped unless
+    /// the proxy accepted the ongoing request, so those of a request that ended before its
+    /// answer came are not taken for the answer to the next one. Hello frames are taken at
+    /// any time.
+    fn receive_frame_bytes(&self, buffer: &mut [u8], len: usize) -> Option<bool> {
+        // Kind, offset in the response or the message and start in the buffer of the current run
+        let mut run: Option<(Run, usize, usize)> = None;
+        let mut decoded = 0;
+        let mut end = None;
+        // Payload bytes of the response received so far, if some arrived
+        let mut progress = None;
+        for i in 0..len {
+            if self.skip.get() > 0 {
+                self.skip.set(self.skip.get() - 1);
+                continue;
+            }
+            // The response and error frames that come are those of the ongoing request
+            let accepted = matches!(self.state.get(), NetworkState::Requesting) && self.accepted.get();
+            let frame_type = self.frame.frame_type();
+            let (kind, offset, byte) = match self.frame.receive(buffer[i]) {
+                FrameEvent::Payload(byte) if frame_type == FRAME_PUSH => {
+                    let offset = self.push_len.get();
+                    self.push_len.set(offset + 1);
+                    (Run::Push, offset, byte)
+                }
+                FrameEvent::Payload(byte) if accepted && frame_type == FRAME_RESPONSE => {
+                    progress = Some(self.frame.received());
+                    match self.response.receive(byte) {
+                        ResponseEvent::Header(offset, byte) => (Run::Headers, offset, byte),
+                        ResponseEvent::Body(offset, byte) => (Run::Body, offset, byte),
+                        ResponseEvent::None => continue,
+                    }
+                }
+                FrameEvent::Payload(code) if accepted && frame_type == FRAME_ERROR => {
+                    self.proxy_error.set(code);
+                    continue;
+                }
+                FrameEvent::Payload(byte) if frame_type == FRAME_HELLO => {
+                    let hello_len = self.hello_len.get();
+                    if hello_len < HELLO_LEN {
+                        let mut hello = self.hello.get();
+                        hello[hello_len] = byte;
+                        self.hello.set(hello);
+                    }
+                    self.hello_len.set(hello_len + 1);
+                    continue;
+                }
+                FrameEvent::End(valid) if frame_type == FRAME_HELLO => {
+                    self.receive_hello(valid);
+                    continue;
+                }
+                FrameEvent::Payload(byte) if frame_type == FRAME_ACCEPTED => {
+                    let accepted_len = self.accepted_len.get();
+                    if accepted_len < ACCEPTED_LEN {
+                        let id = self.accepted_id.get() & !(0xff << (8 * accepted_len));
+                        self.accepted_id.set(id | (byte as u32) << (8 * accepted_len));
+                    }
+                    self.accepted_len.set(accepted_len + 1);
+                    continue;
+                }
+                FrameEvent::End(valid) if frame_type == FRAME_ACCEPTED => {
+                    self.receive_accepted(valid);
+                    continue;
+                }
+                FrameEvent::End(valid) if frame_type == FRAME_PUSH => {
+                    // The message must be complete before its end is reported
+                    self.deliver(buffer, run, decoded);
+                    run = None;
+                    let push_len = self.push_len.get();
+                    self.push_len.set(0);
+                    let result = if valid { Ok(push_len) } else { Err(NetworkError::MalformedResponse) };
+                    self.push_client.map(|push_client| push_client.push_done(result));
+                    continue;
+                }
+                FrameEvent::End(valid)
+                    if accepted && (frame_type == FRAME_RESPONSE || frame_type == FRAME_ERROR) =>
+                {
+                    end = Some(valid);
+                    break;
+                }
+                // Frames of other types are skipped
+                _ => continue,
+            };
+
+            match run {
+                Some((run_kind, _, _)) if run_kind == kind => {}
+                _ => {
+                    self.deliver(buffer, run, decoded);
+                    run = Some((kind, offset, decoded));
                 }
             }
-            _ => Err((buffer, ErrorCode::NOSUPPORT)),
+            buffer[decoded] = byte;
+            decoded += 1;
         }
+        self.deliver(buffer, run, decoded);
+        if let Some(received) = progress {
+            self.client.map(|client| client.response_progress(received, self.frame.len()));
+        }
+        end
     }
 
-    fn allocate_grant(&self, process_id: ProcessId) -> Result<(), Error> {
-        self.grant_access.enter(process_id, |_app_storage, _upcalls_table| {})
+    /// Give a run of decoded bytes to its client
+    fn deliver(&self, buffer: &[u8], run: Option<(Run, usize, usize)>, end: usize) {
+        if let Some((kind, offset, start)) = run {
+            let data = &buffer[start..end];
+            match kind {
+                Run::Headers => self.client.map(|client| client.response_headers(offset, data)),
+                Run::Body => self.client.map(|client| client.response_body(offset, data)),
+                Run::Push => self.push_client.map(|push_client| push_client.push_data(offset, data)),
+            };
+        }
     }
 }
 
-impl<'a> TransmitClient for Network<'a> {
-    fn transmitted_buffer(
-        &self,
-        tx_buffer: &'static mut [u8],
-        _tx_len: usize,
-        rval: Result<(), ErrorCode>,
-    ) {
-        match rval {
-            Ok(()) => {
-                // Start reading the response from UART
-                if let Err((error, buffer)) = self.uart.receive_buffer(tx_buffer, 1) {
-                    self.buffer.replace(buffer);
-                    if let NetworkState::Requesting(process_id) = self.state.get() {
-                        let _ = self.grant_access.enter(process_id, |_, upcalls_table| {
-                            let _ = upcalls_table
-                                .schedule_upcall(0, (into_statuscode(Err(error)), 0, 0));
-                        });
-                    }
-                    self.state.set(NetworkState::Idle);
-                }
+impl<'a, A: Alarm<'a>> Http<'a> for Network<'a, A> {
+    fn set_client(&self, client: &'a dyn HttpClient) {
+        self.client.set(client);
+    }
+
+    fn request(&self, method: Method, content_type: ContentType, timeout_ms: u32) -> Result<(), ErrorCode> {
+        let proxy = match self.link.get() {
+            LinkState::Up(proxy) => proxy,
+            // Fail fast until a compatible proxy answers the handshake
+            _ => return Err(ErrorCode::OFF),
+        };
+        if !matches!(self.state.get(), NetworkState::Idle) {
+            return Err(ErrorCode::BUSY);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+
+        // The client writes its request into the kernel buffer
+        let res = {
+            let mut request = RequestWriter::new(&mut *buffer, method, content_type);
+            self.client
+                .map_or(Err(ErrorCode::FAIL), |client| client.write_request(&mut request))
+                .and_then(|()| request.finish())
+                .map(|len| (len, request.segment_len()))
+        };
+        let res = res.and_then(|(len, segment_len)| {
+            self.authorize(&mut buffer[..], len).map(|len| (len, segment_len))
+        });
+        // Paced responses are announced before the request
+        let res = res.and_then(|(len, segment_len)| match segment_len {
+            0 => Ok((len, segment_len)),
+            _ if !proxy.pacing => Err(ErrorCode::NOSUPPORT),
+            _ => write_line(&mut buffer[..], len, b"PACE ", segment_len).map(|len| (len, segment_len)),
+        });
+        // The ID goes first, so that the proxy knows which request its answer is for
+        let request_id = self.request_id.get().wrapping_add(1);
+        let res = res.and_then(|(len, segment_len)| {
+            write_line(&mut buffer[..], len, b"ID ", request_id as usize).map(|len| (len, segment_len))
+        });
+        let (len, segment_len) = match res {
+            Ok((len, _)) if len > proxy.max_request_len => {
+                self.buffer.replace(buffer);
+                return Err(ErrorCode::SIZE);
             }
+            Ok(res) => res,
             Err(error) => {
-                self.buffer.replace(tx_buffer);
-                if let NetworkState::Requesting(process_id) = self.state.get() {
-                    let _ = self.grant_access.enter(process_id, |_, upcalls_table| {
-                        let _ =
-                            upcalls_table.schedule_upcall(0, (into_statuscode(Err(error)), 0, 0));
-                    });
-                }
-                self.state.set(NetworkState::Idle);
+                self.buffer.replace(buffer);
+                return Err(error);
             }
+        };
+
+        self.response.reset();
+        if !self.receiving.get() {
+            // Otherwise a pushed message may be under way
+            self.frame.reset();
+        }
+        self.accepted.set(false);
+        if let Err((error, buffer)) = self.transport.transmit(buffer, len) {
+            self.buffer.replace(buffer);
+            Err(error)
+        } else {
+            self.request_id.set(request_id);
+            self.state.set(NetworkState::Requesting);
+            self.segment_len.set(segment_len);
+            let timeout_ms = if timeout_ms > 0 { timeout_ms } else { DEFAULT_TIMEOUT_MS };
+            self.timeout_ms.set(timeout_ms);
+            self.alarm.set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(timeout_ms));
+            // Start reading the response from the proxy
+            self.listen();
+            Ok(())
+        }
+    }
+
+    fn cancel(&self) -> Result<bool, ErrorCode> {
+        if let NetworkState::Requesting = self.state.get() {
+            // The request ends from the alarm, rather than from within this call
+            self.state.set(NetworkState::Ending(Err(HttpError::Kernel(ErrorCode::CANCEL))));
+            self.alarm.set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(0));
+            Ok(true)
+        } else {
+            // Nothing to cancel
+            Err(ErrorCode::ALREADY)
         }
     }
+
+    fn next_segment(&self) -> Result<(), ErrorCode> {
+        if !matches!(self.state.get(), NetworkState::Requesting) || !self.held.get() {
+            return Err(ErrorCode::ALREADY);
+        }
+        self.held.set(false);
+        self.send_next();
+        Ok(())
+    }
 }
 
-impl<'a> ReceiveClient for Network<'a> {
-    fn received_buffer(
-        &self,
-        rx_buffer: &'static mut [u8],
-        rx_len: usize,
-        rval: Result<(), ErrorCode>,
-        _error: kernel::hil::uart::Error,
-    ) {
+impl<'a, A: Alarm<'a>> ProxyLink<'a> for Network<'a, A> {
+    fn set_link_client(&self, client: &'a dyn LinkClient) {
+        self.link_client.set(client);
+    }
+
+    fn link_state(&self) -> LinkState {
+        self.link.get()
+    }
+
+    fn handshake(&self) -> Result<(), ErrorCode> {
+        if !matches!(self.state.get(), NetworkState::Idle) {
+            return Err(ErrorCode::BUSY);
+        }
+        let buffer = self.buffer.take().ok_or(ErrorCode::NOMEM)?;
+
+        let capabilities = if self.push_client.is_some() { CAPABILITY_PUSH } else { 0 };
+        let len = match write_hello(buffer, self.device_id.get(), capabilities) {
+            Ok(len) => len,
+            Err(error) => {
+                self.buffer.replace(buffer);
+                return Err(error);
+            }
+        };
+
+        if !self.receiving.get() {
+            self.frame.reset();
+        }
+        if let Err((error, buffer)) = self.transport.transmit(buffer, len) {
+            self.buffer.replace(buffer);
+            Err(error)
+        } else {
+            self.state.set(NetworkState::Handshaking);
+            self.alarm.set_alarm(self.alarm.now(), self.alarm.ticks_from_ms(HANDSHAKE_TIMEOUT_MS));
+            self.update_link(LinkState::Handshaking);
+            // Start reading the answer from the proxy
+            self.listen();
+            Ok(())
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> TransportClient for Network<'a, A> {
+    fn transmitted(&self, tx_buffer: &'static mut [u8], _tx_len: usize, rval: Result<(), ErrorCode>) {
+        self.buffer.replace(tx_buffer);
+        match self.state.get() {
+            // The request ended while being sent
+            NetworkState::Ending(result) => self.finish(result),
+            NetworkState::Requesting if rval.is_err() => {
+                // The request could not be sent over the link
+                self.update_link(LinkState::Down);
+                self.finish(Err(HttpError::Network(NetworkError::ProxyUnreachable)));
+            }
+            NetworkState::Handshaking if rval.is_err() && self.link.get() == LinkState::Handshaking => {
+                self.update_link(LinkState::Down);
+            }
+            // The proxy may have answered the handshake already
+            NetworkState::Handshaking => self.end_handshake(),
+            NetworkState::Requesting if self.next_pending.get() => {
+                self.next_pending.set(false);
+                self.send_next();
+            }
+            // Wait for the response from the proxy
+            _ => {}
+        }
+    }
+
+    fn received(&self, rx_buffer: &'static mut [u8], rx_len: usize, rval: Result<(), ErrorCode>) {
+        self.receiving.set(false);
         match rval {
             Ok(()) => {
-                if let NetworkState::Requesting(process_id) = self.state.get() {
-
-                    // Set the current received byte in the response buffer
-                    let _ = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
-                        let _res = app_storage.data_in.mut_enter(|data_in| {
-                            if rx_buffer.len() <= data_in.len() {
-                                data_in[self.response_curr_idx.get()].set(rx_buffer[0]);
-                                self.response_curr_idx.set(self.response_curr_idx.get() + 1);
-                            }
-                            if rx_buffer[0] == 0 && self.response_curr_idx.get() < data_in.len() {
-                                data_in[self.response_curr_idx.get()].set(0);
-                            }
-                        });
-                    });
-
-                    if rx_buffer[0] != 0 {
-                        // Wait for the next byte
-                        if let Err((error, buffer)) = self.uart.receive_buffer(rx_buffer, 1) {
-                            self.buffer.replace(buffer);
-                            if let NetworkState::Requesting(process_id) = self.state.get() {
-                                let _ = self.grant_access.enter(process_id, |_, upcalls_table| {
-                                    let _ = upcalls_table.schedule_upcall(0, (into_statuscode(Err(error)), 0, 0));
-                                });
-                            }
-                            self.state.set(NetworkState::Idle);
-                        }
+                let rx_len = core::cmp::min(rx_len, rx_buffer.len());
+                if let Some(valid) = self.receive_frame_bytes(rx_buffer, rx_len) {
+                    // Finished reading the response
+                    let result = if self.frame.frame_type() == FRAME_RESPONSE {
+                        self.response_result(valid)
                     } else {
-                        // All bytes read
-                        let _ = self.grant_access.enter(process_id, |app_storage, upcalls_table| {
-                            let mut has_body = false;
-                            let mut body_offset: usize = 0;
-
-                            // Extract the response body
-                            let _res = app_storage.data_in.mut_enter(|data_in| {
-                                for i in 0..data_in.len() - 3 {
-                                    if data_in[i].get() == 0 {
-                                        break;
-                                    }
-                                    if data_in[i].get() == '\r' as u8 && 
-                                       data_in[i + 1].get() == '\n' as u8 && 
-                                       data_in[i + 2].get() == '\r' as u8 && 
-                                       data_in[i + 3].get() == '\n' as u8 {
-                                        // "\r\n\r\n" delimits the headers section from the body of the response
-                                        has_body = true;
-                                        body_offset = i + 4;
-                                        break;
-                                    }
-                                }
-                                // Keep only the body in the response buffer; Shift body content to the beginning of the buffer
-                                if has_body {
-                                    for i in body_offset..data_in.len() {
-                                        data_in[i - body_offset].set(data_in[i].get());
-                                    }
-                                }
-                            });
-
-                            if !has_body {
-                                let _ = upcalls_table.schedule_upcall(0, (418, 0, 0));
-                            } else {
-                                let _ = upcalls_table.schedule_upcall(0, (0, rx_len, 0));
-                            }
-                        });
-
-                        // Finished reading the response; reset the state
-                        self.buffer.replace(rx_buffer);
-                        self.state.set(NetworkState::Idle);
-                    }
+                        self.error_result(valid)
+                    };
+                    self.end_request(result);
+                } else {
+                    self.end_segment();
                 }
             }
-            Err(error) => {
-                if let NetworkState::Requesting(process_id) = self.state.get() {
-                    let _ = self.grant_access.enter(process_id, |_, upcalls_table| {
-                        let _ = upcalls_table.schedule_upcall(0, (into_statuscode(Err(error)), 0, 0));
-                    });
+            Err(_) => {
+                // Framing, parity or overrun error: bytes of the frame were lost. Report the
+                // response or the pushed message as malformed and hunt for the start of the
+                // next frame, so that the rest of this one is skipped.
+                self.frame.reset();
+                self.accepted_len.set(0);
+                self.drop_push();
+                if let NetworkState::Requesting = self.state.get() {
+                    self.end_request(Err(HttpError::Network(NetworkError::MalformedResponse)));
+                }
+            }
+        }
+
+        // Wait for the rest of the frame or for the next one
+        self.rx_buffer.replace(rx_buffer);
+        self.listen();
+    }
+}
+
+impl<'a, A: Alarm<'a>> AlarmClient for Network<'a, A> {
+    fn alarm(&self) {
+        match self.state.get() {
+            NetworkState::Requesting => {
+                // The proxy did not answer in time; either it is not there at all
+                // or the server is slow
+                let error = if self.accepted.get() {
+                    NetworkError::Timeout
+                } else {
+                    // Requests fail fast until the proxy answers the handshake again
+                    self.update_link(LinkState::Down);
+                    NetworkError::ProxyUnreachable
+                };
+                self.end_request(Err(HttpError::Network(error)));
+            }
+            NetworkState::Handshaking if self.link.get() == LinkState::Handshaking => {
+                // No proxy answered
+                self.update_link(LinkState::Down);
+            }
+            // Time for another handshake
+            NetworkState::Idle if self.link.get() == LinkState::Down => {
+                let _ = self.handshake();
+            }
+            // The request was canceled; unless it is still being sent
+            NetworkState::Ending(result) if self.buffer.is_some() => self.finish(result),
+            _ => {}
+        }
+    }
+}
+
+/// Write the handshake of the driver into `buffer`; returns its length
+fn write_hello(buffer: &mut [u8], device_id: u64, capabilities: u8) -> Result<usize, ErrorCode> {
+    const HELLO: &[u8] = b"HELLO ";
+    // Prefix, version, device ID, capabilities, separators and line break
+    let len = HELLO.len() + 2 + 1 + 16 + 1 + 2 + 2;
+    if buffer.len() < len {
+        return Err(ErrorCode::SIZE);
+    }
+
+    buffer[..HELLO.len()].copy_from_slice(HELLO);
+    let mut i = HELLO.len();
+    write_hex(&mut buffer[i..i + 2], PROTOCOL_VERSION as u64);
+    buffer[i + 2] = b' ';
+    i += 3;
+    write_hex(&mut buffer[i..i + 16], device_id);
+    buffer[i + 16] = b' ';
+    i += 17;
+    write_hex(&mut buffer[i..i + 2], capabilities as u64);
+    buffer[i + 2..i + 4].copy_from_slice(b"\r\n");
+    Ok(len)
+}
+
+/// Put an Authorization header with `token` after the request line of the request of `len`
+/// bytes in `buffer`, if its address is on `origin`; returns the new length
+fn write_authorization(buffer: &mut [u8], len: usize, origin: &[u8], token: &[u8]) -> Result<usize, ErrorCode> {
+    const AUTHORIZATION: &[u8] = b"Authorization: Bearer ";
+    let line_end = match buffer[..len].windows(2).position(|end| end == b"\r\n") {
+        Some(position) => position + 2,
+        None => return Ok(len),
+    };
+    // The address follows the method
+    let address = match buffer[..line_end - 2].iter().position(|&c| c == ' ' as u8) {
+        Some(space) => &buffer[space + 1..line_end - 2],
+        None => return Ok(len),
+    };
+    if !is_same_origin(address, origin) {
+        return Ok(len);
+    }
+
+    let header_len = AUTHORIZATION.len() + token.len() + 2;
+    if len + header_len > buffer.len() {
+        return Err(ErrorCode::SIZE);
+    }
+    buffer.copy_within(line_end..len, line_end + header_len);
+    let mut i = line_end;
+    buffer[i..i + AUTHORIZATION.len()].copy_from_slice(AUTHORIZATION);
+    i += AUTHORIZATION.len();
+    buffer[i..i + token.len()].copy_from_slice(token);
+    i += token.len();
+    buffer[i..i + 2].copy_from_slice(b"\r\n");
+    Ok(len + header_len)
+}
+
+/// Whether `address` is on `origin` (e.g. "http://host:3000"); the path of the address
+/// must start right after the origin, so that "http://host:30001" is not on it
+fn is_same_origin(address: &[u8], origin: &[u8]) -> bool {
+    address.len() >= origin.len()
+        && address[..origin.len()].eq_ignore_ascii_case(origin)
+        && matches!(address.get(origin.len()).copied(), None | Some(b'/' | b'?' | b'#'))
+}
+
+/// Put a line with `keyword` and `value` (eight hex digits), e.g. the PACE line of a paced
+/// response, before the request of `len` bytes in `buffer`; returns the new length
+fn write_line(buffer: &mut [u8], len: usize, keyword: &[u8], value: usize) -> Result<usize, ErrorCode> {
+    let line_len = keyword.len() + 8 + 2;
+    if len + line_len > buffer.len() || value > u32::MAX as usize {
+        return Err(ErrorCode::SIZE);
+    }
+
+    buffer.copy_within(0..len, line_len);
+    buffer[..keyword.len()].copy_from_slice(keyword);
+    write_hex(&mut buffer[keyword.len()..keyword.len() + 8], value as u64);
+    buffer[keyword.len() + 8..line_len].copy_from_slice(b"\r\n");
+    Ok(len + line_len)
+}
+
+/// Write `value` as lowercase hex digits, filling `out`
+fn write_hex(out: &mut [u8], value: u64) {
+    let digits = out.len();
+    for (i, digit) in out.iter_mut().enumerate() {
+        let nibble = (value >> (4 * (digits - 1 - i))) & 0xF;
+        *digit = b"0123456789abcdef"[nibble as usize];
+    }
+}
+
+/// Parse the numeric code from the status line of a response (e.g. "HTTP/1.1 404 Not Found")
+fn parse_status_code(line: &[u8]) -> Option<usize> {
+    if !line.starts_with("HTTP/".as_bytes()) {
+        return None;
+    }
+
+    // Skip the protocol version
+    let version_len = line.iter().position(|&c| c == ' ' as u8)?;
+
+    // The status code has exactly three digits and is followed by a space or the end of the line
+    let code = line.get(version_len + 1..version_len + 4)?;
+    if !code.iter().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    if line.len() > version_len + 4 && line[version_len + 4] != ' ' as u8 {
+        return None;
+    }
+
+    Some(code.iter().fold(0, |status_code, &digit| status_code * 10 + (digit - '0' as u8) as usize))
+}
+
+/// Value of a header line (e.g. "Transfer-Encoding: chunked") if it has the given name
+fn header_value<'l>(line: &'l [u8], name: &str) -> Option<&'l [u8]> {
+    let name = name.as_bytes();
+    if line.len() <= name.len() || !line[..name.len()].eq_ignore_ascii_case(name) || line[name.len()] != ':' as u8 {
+        return None;
+    }
+
+    // Leave out the whitespace around the value
+    let mut value = &line[name.len() + 1..];
+    while let [b' ' | b'\t', rest @ ..] = value {
+        value = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = value {
+        value = rest;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use kernel::dynamic_deferred_call::{
+        DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient, DynamicDeferredCallClientState,
+    };
+    use kernel::hil::time::{Freq1KHz, Ticks32, Time};
+
+    use super::*;
+    use crate::transport::LoopbackTransport;
+
+    const CHUNKED_HEADERS: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    /// Give the reads of a response to a parser; returns it with the decoded body
+    fn parse(reads: &[&[u8]]) -> (ResponseParser, [u8; 64], usize) {
+        let parser = ResponseParser::new();
+        let mut body = [0; 64];
+        let mut body_len = 0;
+        for read in reads {
+            for &byte in read.iter() {
+                if let ResponseEvent::Body(index, byte) = parser.receive(byte) {
+                    assert_eq!(index, body_len);
+                    body[body_len] = byte;
+                    body_len += 1;
                 }
             }
         }
+        (parser, body, body_len)
+    }
+
+    #[test]
+    fn chunk_size_split_across_reads() {
+        let (parser, body, body_len) = parse(&[
+            CHUNKED_HEADERS,
+            b"1",
+            b"0\r",
+            b"\n0123456789abcdef\r\n0",
+            b"\r\n\r\n",
+        ]);
+        assert_eq!(&body[..body_len], b"0123456789abcdef");
+        assert_eq!(parser.body_len(), 16);
+        assert_eq!(parser.status_code(), Some(200));
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn uppercase_hex_chunk_size() {
+        let (parser, body, body_len) = parse(&[CHUNKED_HEADERS, b"A\r\n0123456789\r\n0\r\n\r\n"]);
+        assert_eq!(&body[..body_len], b"0123456789");
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn chunk_extensions_are_ignored() {
+        let (parser, body, body_len) = parse(&[
+            CHUNKED_HEADERS,
+            b"5;name=value\r\nhello\r\n6 ; quoted=\"a;b\"\r\n world\r\n0;last\r\n\r\n",
+        ]);
+        assert_eq!(&body[..body_len], b"hello world");
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn last_chunk_with_trailers() {
+        let (parser, body, body_len) =
+            parse(&[CHUNKED_HEADERS, b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Checksum: 1\r\n\r\n"]);
+        assert_eq!(&body[..body_len], b"abc");
+        assert!(parser.is_complete());
+    }
+
+    #[test]
+    fn missing_last_chunk_is_incomplete() {
+        let (parser, body, body_len) = parse(&[CHUNKED_HEADERS, b"3\r\nabc\r\n"]);
+        assert_eq!(&body[..body_len], b"abc");
+        assert!(!parser.is_complete());
+
+        // The trailers are not terminated
+        let (parser, _, _) = parse(&[CHUNKED_HEADERS, b"3\r\nabc\r\n0\r\nExpires: never\r\n"]);
+        assert!(!parser.is_complete());
+    }
+
+    #[test]
+    fn bad_chunk_size_line() {
+        // Not a hex digit
+        let (parser, _, body_len) = parse(&[CHUNKED_HEADERS, b"zz\r\nabc\r\n0\r\n\r\n"]);
+        assert_eq!(body_len, 0);
+        assert!(!parser.is_complete());
+
+        // No digits at all
+        let (parser, _, _) = parse(&[CHUNKED_HEADERS, b"3\r\nabc\r\n\r\n0\r\n\r\n"]);
+        assert!(!parser.is_complete());
+
+        // Too large for the chunk size
+        let (parser, _, _) = parse(&[CHUNKED_HEADERS, b"fffffffffffffffffffffffff\r\n"]);
+        assert!(!parser.is_complete());
+
+        // The chunk data is not followed by a line break
+        let (parser, body, body_len) = parse(&[CHUNKED_HEADERS, b"3\r\nabcd\r\n0\r\n\r\n"]);
+        assert_eq!(&body[..body_len], b"abc");
+        assert!(!parser.is_complete());
+    }
+
+    #[test]
+    fn chunked_without_body() {
+        // E.g. the response to a HEAD request
+        let (parser, _, body_len) = parse(&[CHUNKED_HEADERS]);
+        assert_eq!(body_len, 0);
+        assert!(parser.is_complete());
+    }
+
+    /// Alarm that never fires; the requests of the tests end before their timeout
+    struct TestAlarm;
+
+    impl Time for TestAlarm {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+    }
+
+    impl<'a> Alarm<'a> for TestAlarm {
+        fn set_alarm_client(&self, _client: &'a dyn AlarmClient) {}
+
+        fn set_alarm(&self, _reference: Ticks32, _dt: Ticks32) {}
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32::from(0)
+        }
+
+        fn disarm(&self) -> Result<(), ErrorCode> {
+            Ok(())
+        }
+
+        fn is_armed(&self) -> bool {
+            false
+        }
+
+        fn minimum_dt(&self) -> Ticks32 {
+            Ticks32::from(1)
+        }
+    }
+
+    const ADDRESS: &[u8] = b"http://example.com/api";
+
+    /// Sends a GET request to `ADDRESS` and keeps the response body and the result
+    struct TestClient {
+        body: Cell<[u8; 64]>,
+        body_len: Cell<usize>,
+        result: Cell<Option<Result<HttpResponse, HttpError>>>,
+    }
+
+    impl HttpClient for TestClient {
+        fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+            request.write_address(ADDRESS)
+        }
+
+        fn response_headers(&self, _offset: usize, _data: &[u8]) {}
+
+        fn response_body(&self, offset: usize, data: &[u8]) {
+            let mut body = self.body.get();
+            body[offset..offset + data.len()].copy_from_slice(data);
+            self.body.set(body);
+            self.body_len.set(offset + data.len());
+        }
+
+        fn request_done(&self, result: Result<HttpResponse, HttpError>) {
+            self.result.set(Some(result));
+        }
+    }
+
+    /// A `Network` over a `LoopbackTransport`, linked to the proxy
+    struct Loopback {
+        network: &'static Network<'static, TestAlarm>,
+        transport: &'static LoopbackTransport<'static>,
+        handle: DeferredCallHandle,
+        client: &'static TestClient,
+    }
+
+    impl Loopback {
+        fn new() -> Loopback {
+            let deferred_call_clients = Box::leak(Box::new(<[DynamicDeferredCallClientState; 1]>::default()));
+            let deferred_caller = Box::leak(Box::new(DynamicDeferredCall::new(deferred_call_clients)));
+            let transport: &'static LoopbackTransport =
+                Box::leak(Box::new(LoopbackTransport::new(deferred_caller, Box::leak(Box::new([0; 256])))));
+            let handle = deferred_caller.register(transport).unwrap();
+            transport.initialize_callback_handle(handle);
+
+            let network: &'static Network<TestAlarm> = Box::leak(Box::new(Network::new(
+                transport,
+                Box::leak(Box::new(TestAlarm)),
+                Box::leak(Box::new([0; 256])),
+                Box::leak(Box::new([0; 64])),
+            )));
+            transport.set_client(network);
+            let client: &'static TestClient = Box::leak(Box::new(TestClient {
+                body: Cell::new([0; 64]),
+                body_len: Cell::new(0),
+                result: Cell::new(None),
+            }));
+            network.set_client(client);
+
+            let loopback = Loopback {
+                network,
+                transport,
+                handle,
+                client,
+            };
+            network.handshake().unwrap();
+            // Version, no capabilities and requests of up to 1024 bytes
+            loopback.answer(&[frame(FRAME_HELLO, &[PROTOCOL_VERSION, 0, 0x00, 0x04, 0x00, 0x00])]);
+            assert!(matches!(network.link_state(), LinkState::Up(_)));
+            loopback
+        }
+
+        /// Have the proxy send `frames`, and receive them
+        fn answer(&self, frames: &[Vec<u8>]) {
+            self.transport.set_response(frames.concat().leak());
+            // Complete the operations of the transport, as its deferred calls would
+            for _ in 0..100 {
+                self.transport.call(self.handle);
+            }
+        }
+    }
+
+    /// Frame of the proxy with its CRC
+    fn frame(frame_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&FRAME_SYNC);
+        frame.push(frame_type);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(payload);
+        let crc = FrameReceiver::new();
+        crc.crc.set(0xFFFF);
+        for &byte in &frame[FRAME_SYNC.len()..] {
+            crc.update_crc(byte);
+        }
+        frame.extend_from_slice(&crc.crc.get().to_le_bytes());
+        frame
+    }
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+
+    #[test]
+    fn request_through_loopback() {
+        let loopback = Loopback::new();
+        loopback.network.request(Method::Get, ContentType::Json, 0).unwrap();
+        loopback.answer(&[]);
+        assert_eq!(
+            loopback
+                .transport
+                .transmitted_data(|data| data.starts_with(b"ID 00000001\r\nGET http://example.com/api\r\n")),
+            Some(true)
+        );
+        assert!(loopback.client.result.get().is_none());
+
+        loopback.answer(&[frame(FRAME_ACCEPTED, &1u32.to_le_bytes()), frame(FRAME_RESPONSE, RESPONSE)]);
+        match loopback.client.result.get() {
+            Some(Ok(response)) => {
+                assert_eq!(response.status_code, 200);
+                assert_eq!(response.body_len, 5);
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(&loopback.client.body.get()[..loopback.client.body_len.get()], b"hello");
+    }
+
+    #[test]
+    fn frames_of_other_requests_skipped() {
+        let loopback = Loopback::new();
+        loopback.network.request(Method::Get, ContentType::Json, 0).unwrap();
+        loopback.answer(&[
+            // Not accepted
+            frame(FRAME_RESPONSE, b"HTTP/1.1 500 Internal Server Error\r\n\r\n"),
+            // Accepted, but for an earlier request
+            frame(FRAME_ACCEPTED, &0u32.to_le_bytes()),
+            frame(FRAME_ERROR, &[PROXY_ERROR_TIMEOUT]),
+            frame(FRAME_ACCEPTED, &1u32.to_le_bytes()),
+            frame(FRAME_RESPONSE, RESPONSE),
+        ]);
+        assert!(matches!(loopback.client.result.get(), Some(Ok(HttpResponse { status_code: 200, .. }))));
+        assert_eq!(&loopback.client.body.get()[..loopback.client.body_len.get()], b"hello");
+
+        // The proxy failed the next request
+        loopback.network.request(Method::Get, ContentType::Json, 0).unwrap();
+        loopback.answer(&[frame(FRAME_ACCEPTED, &2u32.to_le_bytes()), frame(FRAME_ERROR, &[PROXY_ERROR_DNS_FAILURE])]);
+        assert!(matches!(
+            loopback.client.result.get(),
+            Some(Err(HttpError::Network(NetworkError::DnsFailure)))
+        ));
     }
 }
@@ -0,0 +1,1258 @@
+//! Network Driver
+//!
+//! Lets processes send HTTP requests through a `VirtualHttpDevice` (see `crate::http`)
+//! and receive the messages that the server pushes through the proxy. POST requests that
+//! cannot reach the server can be kept in an offline queue (see `crate::offline_queue`), if
+//! the board has one, and are sent again once a later request succeeds. The processes can
+//! follow the state of the link to the proxy (see `crate::network::ProxyLink`) and extract
+//! fields from the JSON responses (see `crate::json`). On boards that keep an API credential
+//! (see `crate::credential`), a privileged process can provision it; the processes cannot
+//! read it back.
+//!
+//! Commands
+//!     0 -> SUCCESS
+//!     1 -> send request (method: GET = 0, POST = 1, PUT = 2, PATCH = 3, DELETE = 4, HEAD = 5,
+//!                        content type: application/json = 0, application/x-www-form-urlencoded = 1,
+//!                                      text/plain = 2, application/octet-stream = 3,
+//!                                      custom (Content-Type given in the extra headers) = 4)
+//!          only POST, PUT, PATCH and DELETE accept a body
+//!          fails with SIZE if the request does not fit into the kernel buffer or is longer
+//!          than what the proxy accepts, and with OFF while no compatible proxy answered
+//!          the handshake (or reports it in the upcall, if the request was queued)
+//!          while another request is ongoing, the request is queued and sent in turn with
+//!          the requests of the other processes and of the kernel; the buffers must stay
+//!          allowed until the upcall
+//!          fails with ALREADY if the process has a request ongoing or queued
+//!     2 -> set the request timeout of the process (milliseconds, 0 for the default timeout)
+//!     3 -> cancel the ongoing or queued request of the process; its upcall gets the CANCEL status
+//!          fails with ALREADY if the process has no request to cancel
+//!     4 -> send POST request, kept in the offline queue if it cannot reach the server
+//!          (content type as for command 1, tag reported when the request is sent again)
+//!          like command 1, but if the proxy, the DNS or the server cannot be reached (or no
+//!          proxy is connected), the request is stored and its upcall gets the
+//!          NetworkError::Queued status
+//!     5 -> store POST request in the offline queue, without sending it
+//!          (content type as for command 1, tag reported when the request is sent)
+//!          its upcall gets the NetworkError::Queued status once it is stored
+//!          fails with NOSUPPORT if the board has no offline queue, with ALREADY if the
+//!          process has a request ongoing or queued and with BUSY if another request is
+//!          being stored or the queue is still being loaded
+//!     6 -> link state: (state, proxy version | proxy capabilities << 8, longest request
+//!          accepted by the proxy), see upcall 3
+//!          fails with NOSUPPORT if the board does not give the link to the driver
+//!     7 -> send the handshake to the proxy again; upcall 3 reports its result
+//!          fails with BUSY while a request is ongoing
+//!     8 -> set the progress interval of the process: upcall 4 reports the progress of the
+//!          responses each time this many more bytes arrived (0 for no progress upcalls)
+//!     9 -> stream the following responses of the process (1) or not (0): the body comes in
+//!          chunks of up to the size of the response buffer, each reported by upcall 5; the
+//!          proxy sends the next chunk once the process acknowledged the previous one
+//!    10 -> acknowledge the chunk in the response buffer, so that the next one comes
+//!          fails with ALREADY if no chunk waits for the acknowledgement
+//!    11 -> extract a JSON field: writes the value at the key path into the JSON value buffer,
+//!          truncated to its size and zero terminated if there is room; returns (value length,
+//!          value type: string = 0, number = 1, bool = 2, null = 3, object = 4, array = 5)
+//!          strings are unescaped, the other values are written as they are in the document
+//!          fails with INVAL if the path or the document is malformed, with SIZE if the path is
+//!          longer than 64 bytes and with FAIL if the document has no value at the path
+//!    12 -> conditional GET requests for the process (1) or not (0): the driver remembers the
+//!          validator (ETag or Last-Modified) of the body in the response buffer, if it came
+//!          whole in the response to a GET request, and makes the next GET request to the
+//!          same address (up to 128 bytes long) conditional; if the response did not change,
+//!          upcall 0 reports NetworkError::NotModified and the response buffer is left as it was
+//!          the validator is forgotten when another response is written to the response buffer
+//!          and when this is turned off; it is not used if another response buffer is allowed
+//!    13 -> provision the API credential: replaces the origin and the token kept by the
+//!          kernel with the ones allowed by the process, or removes the credential if the
+//!          token is empty; the new credential is used right away and upcall 6 reports
+//!          whether it was saved
+//!          only the provisioning process may use it, once the board started in provisioning
+//!          mode: the board refuses it otherwise (see `crate::credential::ProvisioningFilter`)
+//!          fails with NOSUPPORT if the board keeps no credential, with INVAL if the origin is
+//!          empty or either of them has characters other than visible ASCII, with SIZE if the
+//!          origin is longer than 64 bytes or the token longer than 256 bytes and with BUSY
+//!          while the credential is being loaded or saved
+//!
+//! Allow ReadOnly
+//!     0 -> address
+//!     1 -> request body
+//!     2 -> extra request headers ("Name: value" lines separated by "\r\n")
+//!     3 -> JSON document for command 11, up to its end or to a zero byte
+//!     4 -> key path for command 11: object keys and array indices, e.g. `items[0].name`,
+//!          up to its end or to a zero byte
+//!     5 -> credential origin for command 13 (e.g. "http://192.168.100.62:3000"), up to its
+//!          end or to a zero byte
+//!     6 -> credential token for command 13, up to its end or to a zero byte
+//!
+//! Allow ReadWrite
+//!     0 -> response body
+//!     1 -> response headers (optional): the status line and the headers, as received,
+//!          truncated to the size of the buffer and zero terminated if there is room
+//!     2 -> pushed messages: allowing it subscribes the process to the messages pushed by
+//!          the server; each message is zero terminated and overwrites the previous one
+//!     3 -> JSON value written by command 11
+//!
+//! Upcall
+//!     0 -> request done: (status, HTTP status code, body length)
+//!          chunked bodies (Transfer-Encoding: chunked) are decoded, the body length is the decoded one
+//!          the status is 0, an ErrorCode or a `NetworkError` (0x100 and above):
+//!          CANCEL -> the request was canceled by the process
+//!          NetworkError::ProxyUnreachable -> the proxy did not accept the request before the
+//!                                            request timeout or the link to it failed
+//!          NetworkError::ConnectionRefused -> the server refused the connection of the proxy
+//!          NetworkError::DnsFailure -> the proxy could not resolve the host of the address
+//!          NetworkError::MalformedResponse -> the response is not valid HTTP or was
+//!                                             corrupted on the serial link
+//!          NetworkError::Timeout -> the server did not answer in time
+//!          NetworkError::Truncated -> the body and its terminator did not fit into the response
+//!                                     buffer; it is truncated and not terminated:
+//!                                     (Truncated, HTTP status code, full body length)
+//!          NetworkError::InvalidRequest -> the proxy could not parse the request (e.g. the address)
+//!          NetworkError::ConnectionFailed -> the connection of the proxy to the server failed otherwise
+//!          NetworkError::Queued -> the request was stored in the offline queue
+//!          NetworkError::NotModified -> the response did not change since the one in the response
+//!                                       buffer: (NotModified, 304, 0), see command 12
+//!          (a request that could not be stored gets the error of the failed attempt, or the
+//!           ErrorCode of the queue: NOMEM if it is full, SIZE if the request is too large)
+//!     1 -> message pushed: (status, message length, 0)
+//!          the status is 0 or a `NetworkError`:
+//!          NetworkError::MalformedResponse -> the message was corrupted on the serial link
+//!          NetworkError::Truncated -> the message and its terminator did not fit into the push
+//!                                     buffer; it is truncated and not terminated
+//!     2 -> stored request sent: (tag, status, HTTP status code), to every process
+//!          the status is 0 or the error of the request; a request is kept in the queue
+//!          while the server cannot be reached and dropped if it fails otherwise
+//!     3 -> link state changed: (state, proxy version | proxy capabilities << 8, longest request
+//!          accepted by the proxy), to every process
+//!          the state is 0 (no proxy answered), 1 (handshake ongoing), 2 (connected) or
+//!          3 (the proxy speaks another version of the protocol; only its version is given)
+//!          capabilities: 1 -> the proxy forwards pushed messages, 2 -> it can compress responses,
+//!                        4 -> it can stream responses
+//!     4 -> response progress: (bytes received, response length, 0), with the lengths of the
+//!          response as sent by the server (before chunked bodies are decoded); the last one
+//!          comes when the whole response arrived, before upcall 0
+//!     5 -> chunk of a streamed response in the response buffer: (chunk length, more, 0)
+//!          the chunk is zero terminated if there is room; if `more` is 1, the next chunk comes
+//!          once command 10 acknowledged this one, otherwise this is the last chunk and
+//!          upcall 0 follows with the length of the whole body
+//!          the request timeout does not run while the driver waits for the acknowledgement
+//!     6 -> credential provisioned: (status, 0, 0)
+//!          the status is 0 or the ErrorCode of the flash (the credential is used until the
+//!          next reboot even if it could not be saved)
+//!
+
+use core::cell::Cell;
+
+use enum_primitive::cast::FromPrimitive;
+
+use kernel::errorcode::into_statuscode;
+use kernel::grant::Grant;
+use kernel::process::{Error, ProcessId};
+use kernel::processbuffer::{
+    ReadOnlyProcessBuffer, ReadWriteProcessBuffer, ReadableProcessBuffer, ReadableProcessSlice,
+    WriteableProcessBuffer, WriteableProcessSlice,
+};
+use kernel::syscall::{CommandReturn, SyscallDriver};
+use kernel::utilities::cells::{MapCell, OptionalCell};
+use kernel::ErrorCode;
+
+use crate::credential::{self, CredentialClient, CredentialStore};
+use crate::http::{
+    ContentType, Http, HttpClient, HttpError, HttpResponse, Method, NetworkError, PushClient,
+    RequestWriter, Validator,
+};
+use crate::json::{self, ValueType};
+use crate::network::{LinkClient, LinkState, ProxyLink};
+use crate::offline_queue::{OfflineQueue, OfflineQueueClient};
+
+pub const DRIVER_NUM: usize = 0xa0001;
+/// Command that provisions the API credential, refused to the other processes by the board
+pub const PROVISION_COMMAND: usize = 13;
+
+/// Longest address whose validator is kept
+const MAX_VALIDATED_ADDRESS_LEN: usize = 128;
+
+#[derive(Copy, Clone)]
+struct Request {
+    method: Method,
+    content_type: ContentType,
+    // Tag of the request, if it is stored when it cannot reach the server
+    offline_tag: Option<u32>,
+}
+
+/// Validator of the body in the response buffer, with the address it came from
+#[derive(Copy, Clone)]
+struct CachedValidator {
+    address: [u8; MAX_VALIDATED_ADDRESS_LEN],
+    address_len: usize,
+    // Start and length of the response buffer that holds the body
+    buffer: (usize, usize),
+    validator: Validator,
+}
+
+impl CachedValidator {
+    fn new(address: &[u8], buffer: (usize, usize), validator: Validator) -> CachedValidator {
+        let mut cached = CachedValidator {
+            address: [0; MAX_VALIDATED_ADDRESS_LEN],
+            address_len: address.len(),
+            buffer,
+            validator,
+        };
+        cached.address[..address.len()].copy_from_slice(address);
+        cached
+    }
+
+    /// Whether the body came from `address` into `buffer`
+    fn validates(&self, address: &[u8], buffer: (usize, usize)) -> bool {
+        &self.address[..self.address_len] == address && self.buffer == buffer
+    }
+}
+
+#[derive(Default)]
+pub struct AppStorage {
+    address: ReadOnlyProcessBuffer,
+    data_out: ReadOnlyProcessBuffer,
+    headers: ReadOnlyProcessBuffer,
+    data_in: ReadWriteProcessBuffer,
+    response_headers: ReadWriteProcessBuffer,
+    push_buffer: ReadWriteProcessBuffer,
+    json: ReadOnlyProcessBuffer,
+    json_path: ReadOnlyProcessBuffer,
+    json_value: ReadWriteProcessBuffer,
+    credential_origin: ReadOnlyProcessBuffer,
+    credential_token: ReadOnlyProcessBuffer,
+    timeout_ms: u32,
+    // Number of bytes between progress upcalls, 0 for none
+    progress_interval: usize,
+    // The responses come in chunks
+    streaming: bool,
+    // GET requests are conditional on the validator of the body in the response buffer
+    conditional: bool,
+    validator: Option<CachedValidator>,
+    // Bytes of the ongoing response received at the latest progress upcall
+    progress_reported: usize,
+    // Request waiting for the ongoing one to finish
+    pending: Option<Request>,
+}
+
+pub struct NetworkDriver<'a> {
+    grant_access: Grant<AppStorage, 7>,
+    http: &'a dyn Http<'a>,
+    queue: OptionalCell<&'a dyn OfflineQueue<'a>>,
+    link: OptionalCell<&'a dyn ProxyLink<'a>>,
+    credential: OptionalCell<&'a dyn CredentialStore<'a>>,
+    // Process whose credential is being saved
+    provisioning: OptionalCell<ProcessId>,
+    // Process whose request is ongoing
+    current: OptionalCell<ProcessId>,
+    current_request: Cell<Option<Request>>,
+    // The ongoing response is streamed: the body offset of the chunk in the response buffer
+    // and its length so far
+    streaming: Cell<bool>,
+    chunk_start: Cell<usize>,
+    chunk_len: Cell<usize>,
+    // Address of the ongoing GET request, if it may be conditional, and whether it carries
+    // a validator
+    request_address: MapCell<[u8; MAX_VALIDATED_ADDRESS_LEN]>,
+    request_address_len: Cell<Option<usize>>,
+    condition_sent: Cell<bool>,
+    // Process whose request is being stored in the offline queue, with the error of the
+    // attempt to send it, if any
+    storing: OptionalCell<(ProcessId, Option<HttpError>)>,
+    // Process that sent the latest request, for taking turns between processes
+    last_process_id: Cell<usize>,
+}
+
+impl<'a> NetworkDriver<'a> {
+    pub fn new(grant_access: Grant<AppStorage, 7>, http: &'a dyn Http<'a>) -> NetworkDriver<'a> {
+        NetworkDriver {
+            grant_access,
+            http,
+            queue: OptionalCell::empty(),
+            link: OptionalCell::empty(),
+            credential: OptionalCell::empty(),
+            provisioning: OptionalCell::empty(),
+            current: OptionalCell::empty(),
+            current_request: Cell::new(None),
+            streaming: Cell::new(false),
+            chunk_start: Cell::new(0),
+            chunk_len: Cell::new(0),
+            request_address: MapCell::new([0; MAX_VALIDATED_ADDRESS_LEN]),
+            request_address_len: Cell::new(None),
+            condition_sent: Cell::new(false),
+            storing: OptionalCell::empty(),
+            last_process_id: Cell::new(0),
+        }
+    }
+
+    /// Keep the POST requests that cannot reach the server in `queue`
+    pub fn set_offline_queue(&self, queue: &'a dyn OfflineQueue<'a>) {
+        self.queue.set(queue);
+    }
+
+    /// Let the processes follow the link to the proxy
+    pub fn set_proxy_link(&self, link: &'a dyn ProxyLink<'a>) {
+        self.link.set(link);
+    }
+
+    /// Let the provisioning process replace the credential kept in `store`
+    pub fn set_credential_store(&self, store: &'a dyn CredentialStore<'a>) {
+        self.credential.set(store);
+    }
+
+    /// Report the end of the request of a process
+    fn upcall(&self, process_id: ProcessId, status: usize, status_code: usize, len: usize) {
+        let _ = self.grant_access.enter(process_id, |_, upcalls_table| {
+            let _ = upcalls_table.schedule_upcall(0, (status, status_code, len));
+        });
+    }
+
+    /// Cancel the ongoing or queued request of a process
+    fn cancel_request(&self, process_id: ProcessId) -> Result<(), ErrorCode> {
+        if self.current.contains(&process_id) {
+            if !self.http.cancel()? {
+                // It was not sent yet, so no callback will end it
+                self.current.clear();
+                self.upcall(process_id, into_statuscode(Err(ErrorCode::CANCEL)), 0, 0);
+                self.send_next_request();
+            }
+            return Ok(());
+        }
+
+        let res = self.grant_access.enter(process_id, |app_storage, upcalls_table| {
+            if app_storage.pending.take().is_some() {
+                let _ = upcalls_table.schedule_upcall(0, (into_statuscode(Err(ErrorCode::CANCEL)), 0, 0));
+                Ok(())
+            } else {
+                // Nothing to cancel
+                Err(ErrorCode::ALREADY)
+            }
+        });
+        match res {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Send the request of a process, or queue it if another request is ongoing
+    fn start_request(&self, process_id: ProcessId, request: Request) -> Result<(), ErrorCode> {
+        if self.current.is_none() {
+            self.send_request(process_id, request)
+        } else {
+            self.queue_request(process_id, request)
+        }
+    }
+
+    /// Whether the process has a request ongoing, queued or being stored
+    fn has_request(&self, process_id: ProcessId) -> bool {
+        self.current.contains(&process_id)
+            || self.storing.map_or(false, |(storing, _error)| *storing == process_id)
+            || self
+                .grant_access
+                .enter(process_id, |app_storage, _upcalls_table| app_storage.pending.is_some())
+                .unwrap_or(false)
+    }
+
+    /// Queue the request of a process until the ongoing request is done
+    fn queue_request(&self, process_id: ProcessId, request: Request) -> Result<(), ErrorCode> {
+        if self.current.contains(&process_id) {
+            return Err(ErrorCode::ALREADY);
+        }
+
+        let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+            if app_storage.pending.is_some() {
+                Err(ErrorCode::ALREADY)
+            } else {
+                app_storage.pending = Some(request);
+                Ok(())
+            }
+        });
+        match res {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Send the queued requests, one process at a time, in the order of their IDs
+    /// starting after the process that sent the latest request
+    fn send_next_request(&self) {
+        while self.current.is_none() {
+            let last_process_id = self.last_process_id.get();
+            let mut next: Option<ProcessId> = None;
+            for app in self.grant_access.iter() {
+                let process_id = app.processid();
+                if app.enter(|app_storage, _upcalls_table| app_storage.pending.is_none()) {
+                    continue;
+                }
+                // Processes after the latest one come first
+                let key = |process_id: ProcessId| (process_id.id() <= last_process_id, process_id.id());
+                if next.map_or(true, |next| key(process_id) < key(next)) {
+                    next = Some(process_id);
+                }
+            }
+
+            let process_id = match next {
+                Some(process_id) => process_id,
+                None => break,
+            };
+            let request = self
+                .grant_access
+                .enter(process_id, |app_storage, _upcalls_table| app_storage.pending.take());
+            if let Ok(Some(request)) = request {
+                if let Err(error) = self.send_request(process_id, request) {
+                    if !self.store_unreachable(process_id, Some(request), HttpError::Kernel(error)) {
+                        self.upcall(process_id, into_statuscode(Err(error)), 0, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hand the request of the process to the network
+    fn send_request(&self, process_id: ProcessId, request: Request) -> Result<(), ErrorCode> {
+        let timeout_ms = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+            app_storage.progress_reported = 0;
+            app_storage.timeout_ms
+        })?;
+
+        self.current.set(process_id);
+        self.current_request.set(Some(request));
+        self.last_process_id.set(process_id.id());
+        let res = self.http.request(request.method, request.content_type, timeout_ms);
+        if res.is_err() {
+            self.current.clear();
+            self.current_request.set(None);
+        }
+        res
+    }
+
+    /// Store the request of the process in the offline queue; `error` is the error of the
+    /// attempt to send it, reported if it cannot be stored
+    fn store_request(
+        &self,
+        process_id: ProcessId,
+        tag: u32,
+        content_type: ContentType,
+        error: Option<HttpError>,
+    ) -> Result<(), ErrorCode> {
+        let queue = self.queue.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        if self.storing.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        // The queue writes the request right away, see `write_record`
+        self.storing.set((process_id, error));
+        let res = queue.store(tag, content_type);
+        if res.is_err() {
+            self.storing.clear();
+        }
+        res
+    }
+
+    /// Store the request of the process in the offline queue if it failed before reaching
+    /// the server and the process asked for it; returns whether it is being stored, in which
+    /// case `stored` sends the upcall
+    fn store_unreachable(&self, process_id: ProcessId, request: Option<Request>, error: HttpError) -> bool {
+        match request {
+            Some(Request {
+                content_type,
+                offline_tag: Some(tag),
+                ..
+            }) if error.is_unreachable() => self.store_request(process_id, tag, content_type, Some(error)).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Write the value at the key path of the process, in its JSON document, into its JSON
+    /// value buffer; returns the value length and type
+    fn extract_json(&self, process_id: ProcessId) -> Result<(usize, ValueType), ErrorCode> {
+        let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+            let mut path = [0; json::MAX_PATH_LEN];
+            let path_len = app_storage.json_path.enter(|json_path| {
+                let len = (0..json_path.len())
+                    .position(|i| json_path[i].get() == 0)
+                    .unwrap_or(json_path.len());
+                if len > path.len() {
+                    return Err(ErrorCode::SIZE);
+                }
+                for (i, byte) in path[..len].iter_mut().enumerate() {
+                    *byte = json_path[i].get();
+                }
+                Ok(len)
+            })??;
+
+            app_storage
+                .json
+                .enter(|document| {
+                    let bytes = (0..document.len())
+                        .map(|i| document[i].get())
+                        .take_while(|&byte| byte != 0);
+                    app_storage.json_value.mut_enter(|value| {
+                        // The bytes that do not fit are dropped; the full length is returned
+                        let mut len = 0;
+                        let value_type = json::extract(bytes, &path[..path_len], |byte| {
+                            if len < value.len() {
+                                value[len].set(byte);
+                            }
+                            len += 1;
+                        })
+                        .map_err(ErrorCode::from)?;
+                        if len < value.len() {
+                            value[len].set(0);
+                        }
+                        Ok((len, value_type))
+                    })
+                })
+                .and_then(|res| res)
+                .map_err(ErrorCode::from)
+                .and_then(|res| res)
+        });
+        match res {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replace the credential with the origin and the token allowed by the process
+    fn provision(&self, process_id: ProcessId) -> Result<(), ErrorCode> {
+        let store = self.credential.extract().ok_or(ErrorCode::NOSUPPORT)?;
+        if self.provisioning.is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        // The store keeps its own copy, the process buffers are only read here
+        let mut origin = [0; credential::MAX_ORIGIN_LEN];
+        let mut token = [0; credential::MAX_TOKEN_LEN];
+        let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+            let origin_len = app_storage
+                .credential_origin
+                .enter(|buffer| copy_string(buffer, &mut origin))
+                .map_err(ErrorCode::from)
+                .and_then(|res| res)?;
+            let token_len = app_storage
+                .credential_token
+                .enter(|buffer| copy_string(buffer, &mut token))
+                .map_err(ErrorCode::from)
+                .and_then(|res| res)?;
+            Ok((origin_len, token_len))
+        });
+        let (origin_len, token_len) = match res {
+            Ok(res) => res?,
+            Err(err) => return Err(err.into()),
+        };
+
+        store.provision(&origin[..origin_len], &token[..token_len])?;
+        self.provisioning.set(process_id);
+        Ok(())
+    }
+
+    /// Write the address, the extra headers and the body of the request of the process
+    fn write_process_request(&self, process_id: ProcessId, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+        let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+            app_storage
+                .address
+                .enter(|address| request.write_address(address))
+                .map_err(ErrorCode::from)
+                .and_then(|res| res)?;
+
+            // Extra headers of the process
+            if app_storage.headers.len() > 0 {
+                app_storage
+                    .headers
+                    .enter(|headers| request.write_headers(headers))
+                    .map_err(ErrorCode::from)
+                    .and_then(|res| res)?;
+            }
+
+            if app_storage.data_out.len() > 0 {
+                app_storage
+                    .data_out
+                    .enter(|data_out| request.write_body(data_out))
+                    .map_err(ErrorCode::from)
+                    .and_then(|res| res)?;
+            }
+            Ok(())
+        });
+        match res {
+            Ok(res) => res,
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<'a> HttpClient for NetworkDriver<'a> {
+    fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+        let process_id = self.current.extract().ok_or(ErrorCode::FAIL)?;
+        self.write_process_request(process_id, request)?;
+
+        // GET requests to the address of the body in the response buffer are conditional
+        self.request_address_len.set(None);
+        self.condition_sent.set(false);
+        let validator = self
+            .grant_access
+            .enter(process_id, |app_storage, _upcalls_table| {
+                if !app_storage.conditional || request.method() != Method::Get {
+                    return None;
+                }
+                let len = app_storage
+                    .address
+                    .enter(|address| self.request_address.map(|buffer| copy_address(address, buffer)))
+                    .ok()
+                    .flatten()
+                    .flatten()?;
+                self.request_address_len.set(Some(len));
+                let cached = app_storage.validator.as_ref()?;
+                let data_in = (app_storage.data_in.ptr() as usize, app_storage.data_in.len());
+                if self
+                    .request_address
+                    .map_or(false, |address| cached.validates(&address[..len], data_in))
+                {
+                    Some(cached.validator)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(None);
+        if let Some(validator) = validator {
+            request.write_condition(&validator)?;
+            self.condition_sent.set(true);
+        }
+
+        // Streamed responses come in segments that fit into the response buffer
+        let segment_len = self
+            .grant_access
+            .enter(process_id, |app_storage, _upcalls_table| {
+                match (app_storage.streaming, app_storage.data_in.len()) {
+                    // No buffer for the chunks
+                    (true, 0) => Err(ErrorCode::INVAL),
+                    (true, len) => Ok(len),
+                    (false, _) => Ok(0),
+                }
+            })
+            .map_err(ErrorCode::from)
+            .and_then(|res| res)?;
+        request.set_segment_len(segment_len);
+        self.streaming.set(segment_len > 0);
+        self.chunk_start.set(0);
+        self.chunk_len.set(0);
+        Ok(())
+    }
+
+    fn response_headers(&self, offset: usize, data: &[u8]) {
+        self.current.map(|process_id| {
+            let _ = self.grant_access.enter(*process_id, |app_storage, _upcalls_table| {
+                // The bytes that do not fit are dropped
+                let _res = app_storage.response_headers.mut_enter(|response_headers| {
+                    for (i, &byte) in data.iter().enumerate() {
+                        if offset + i < response_headers.len() {
+                            response_headers[offset + i].set(byte);
+                        }
+                    }
+                });
+            });
+        });
+    }
+
+    fn response_body(&self, offset: usize, data: &[u8]) {
+        self.current.map(|process_id| {
+            // A streamed body goes to the beginning of the buffer, one chunk at a time
+            let start = if self.streaming.get() { self.chunk_start.get() } else { 0 };
+            let _ = self.grant_access.enter(*process_id, |app_storage, _upcalls_table| {
+                // The body of the validator is overwritten
+                app_storage.validator = None;
+                // The bytes that do not fit are dropped; the full length is reported at the end
+                let _res = app_storage.data_in.mut_enter(|data_in| {
+                    for (i, &byte) in data.iter().enumerate() {
+                        if offset - start + i < data_in.len() {
+                            data_in[offset - start + i].set(byte);
+                        }
+                    }
+                });
+            });
+            self.chunk_len.set(core::cmp::max(self.chunk_len.get(), offset - start + data.len()));
+        });
+    }
+
+    fn response_progress(&self, received: usize, total: usize) {
+        self.current.map(|process_id| {
+            let _ = self.grant_access.enter(*process_id, |app_storage, upcalls_table| {
+                let interval = app_storage.progress_interval;
+                if interval > 0
+                    && (received - app_storage.progress_reported >= interval || received == total)
+                {
+                    app_storage.progress_reported = received;
+                    let _ = upcalls_table.schedule_upcall(4, (received, total, 0));
+                }
+            });
+        });
+    }
+
+    fn segment_done(&self) -> bool {
+        let chunk_len = self.chunk_len.get();
+        if !self.streaming.get() || chunk_len == 0 {
+            // Nothing for the process yet
+            return true;
+        }
+
+        self.current.map(|process_id| {
+            let _ = self.grant_access.enter(*process_id, |app_storage, upcalls_table| {
+                let _res = app_storage.data_in.mut_enter(|data_in| {
+                    if chunk_len < data_in.len() {
+                        data_in[chunk_len].set(0);
+                    }
+                });
+                let _ = upcalls_table.schedule_upcall(5, (chunk_len, 1, 0));
+            });
+        });
+        self.chunk_start.set(self.chunk_start.get() + chunk_len);
+        self.chunk_len.set(0);
+        // Wait for the process to acknowledge the chunk
+        false
+    }
+
+    fn request_done(&self, result: Result<HttpResponse, HttpError>) {
+        let process_id = match self.current.take() {
+            Some(process_id) => process_id,
+            None => return,
+        };
+        let request = self.current_request.take();
+        let streaming = self.streaming.replace(false);
+        let chunk_len = self.chunk_len.get();
+        let address_len = self.request_address_len.take();
+        let condition_sent = self.condition_sent.replace(false);
+
+        match result {
+            Ok(_) => {
+                // The server can be reached again
+                self.queue.map(|queue| queue.flush());
+            }
+            Err(error) => {
+                if self.store_unreachable(process_id, request, error) {
+                    self.send_next_request();
+                    return;
+                }
+            }
+        }
+
+        let _ = self.grant_access.enter(process_id, |app_storage, upcalls_table| {
+            match result {
+                // The response buffer still holds the body of the validator
+                Ok(response) if condition_sent && response.status_code == 304 => {}
+                Ok(response) => {
+                    // Only a body kept whole in the response buffer can stand for later responses
+                    let kept =
+                        !streaming && response.status_code == 200 && response.body_len < app_storage.data_in.len();
+                    let data_in = (app_storage.data_in.ptr() as usize, app_storage.data_in.len());
+                    app_storage.validator = match (address_len, response.validator) {
+                        (Some(len), Some(validator)) if kept => self
+                            .request_address
+                            .map(|address| CachedValidator::new(&address[..len], data_in, validator)),
+                        _ => None,
+                    };
+                }
+                // The body received before the error, if any, was dropped with the validator
+                Err(_) => {}
+            }
+            match result {
+                Ok(response) if condition_sent && response.status_code == 304 => {
+                    // The response buffer still holds the body of the earlier response
+                    let _res = app_storage.response_headers.mut_enter(|response_headers| {
+                        if response.headers_len < response_headers.len() {
+                            response_headers[response.headers_len].set(0);
+                        }
+                    });
+                    let _ = upcalls_table
+                        .schedule_upcall(0, (NetworkError::NotModified.into(), response.status_code, 0));
+                }
+                Ok(response) if streaming => {
+                    // The last chunk, then the end of the response
+                    let _res = app_storage.response_headers.mut_enter(|response_headers| {
+                        if response.headers_len < response_headers.len() {
+                            response_headers[response.headers_len].set(0);
+                        }
+                    });
+                    let _res = app_storage.data_in.mut_enter(|data_in| {
+                        if chunk_len < data_in.len() {
+                            data_in[chunk_len].set(0);
+                        }
+                    });
+                    let _ = upcalls_table.schedule_upcall(5, (chunk_len, 0, 0));
+                    let _ = upcalls_table.schedule_upcall(0, (0, response.status_code, response.body_len));
+                }
+                Ok(response) => {
+                    // Zero terminate the headers and the body, if there is room
+                    let _res = app_storage.response_headers.mut_enter(|response_headers| {
+                        if response.headers_len < response_headers.len() {
+                            response_headers[response.headers_len].set(0);
+                        }
+                    });
+                    let body_len = response.body_len;
+                    let truncated = app_storage
+                        .data_in
+                        .mut_enter(|data_in| zero_terminate(data_in, body_len))
+                        .unwrap_or(body_len > 0);
+
+                    if truncated {
+                        // Only the beginning of the body was kept; report the full
+                        // body length so that the app can retry with a larger buffer
+                        let _ = upcalls_table
+                            .schedule_upcall(0, (NetworkError::Truncated.into(), response.status_code, body_len));
+                    } else {
+                        let _ = upcalls_table.schedule_upcall(0, (0, response.status_code, body_len));
+                    }
+                }
+                Err(error) => {
+                    let _ = upcalls_table.schedule_upcall(0, (error.into(), 0, 0));
+                }
+            }
+        });
+
+        self.send_next_request();
+    }
+}
+
+impl<'a> PushClient for NetworkDriver<'a> {
+    fn push_data(&self, offset: usize, data: &[u8]) {
+        // Every subscribed process gets a copy of the message
+        for app in self.grant_access.iter() {
+            app.enter(|app_storage, _upcalls_table| {
+                let _res = app_storage.push_buffer.mut_enter(|push_buffer| {
+                    for (i, &byte) in data.iter().enumerate() {
+                        if offset + i < push_buffer.len() {
+                            push_buffer[offset + i].set(byte);
+                        }
+                    }
+                });
+            });
+        }
+    }
+
+    fn push_done(&self, result: Result<usize, NetworkError>) {
+        for app in self.grant_access.iter() {
+            app.enter(|app_storage, upcalls_table| {
+                if app_storage.push_buffer.len() == 0 {
+                    // Not subscribed
+                    return;
+                }
+
+                let (status, len) = match result {
+                    Ok(len) => {
+                        let truncated = app_storage
+                            .push_buffer
+                            .mut_enter(|push_buffer| zero_terminate(push_buffer, len))
+                            .unwrap_or(true);
+                        (if truncated { NetworkError::Truncated.into() } else { 0 }, len)
+                    }
+                    Err(error) => (error.into(), 0),
+                };
+                let _ = upcalls_table.schedule_upcall(1, (status, len, 0));
+            });
+        }
+    }
+}
+
+impl<'a> OfflineQueueClient for NetworkDriver<'a> {
+    fn write_record(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+        let (process_id, _error) = self.storing.extract().ok_or(ErrorCode::FAIL)?;
+        self.write_process_request(process_id, request)
+    }
+
+    fn stored(&self, result: Result<(), ErrorCode>) {
+        if let Some((process_id, error)) = self.storing.take() {
+            let status = match (result, error) {
+                (Ok(()), _) => NetworkError::Queued.into(),
+                (Err(_), Some(error)) => error.into(),
+                (Err(error), None) => into_statuscode(Err(error)),
+            };
+            self.upcall(process_id, status, 0, 0);
+        }
+    }
+
+    fn flushed(&self, tag: u32, result: Result<HttpResponse, HttpError>) {
+        let (status, status_code) = match result {
+            Ok(response) => (0, response.status_code),
+            Err(error) => (error.into(), 0),
+        };
+        for app in self.grant_access.iter() {
+            app.enter(|_app_storage, upcalls_table| {
+                let _ = upcalls_table.schedule_upcall(2, (tag as usize, status, status_code));
+            });
+        }
+    }
+}
+
+impl<'a> LinkClient for NetworkDriver<'a> {
+    fn link_changed(&self, state: LinkState) {
+        let (state_num, proxy, max_request_len) = link_state_values(state);
+        for app in self.grant_access.iter() {
+            app.enter(|_app_storage, upcalls_table| {
+                let _ = upcalls_table.schedule_upcall(3, (state_num, proxy, max_request_len));
+            });
+        }
+
+        if let LinkState::Up(_) = state {
+            // Send the requests kept while the proxy was away
+            self.queue.map(|queue| queue.flush());
+        }
+    }
+}
+
+impl<'a> CredentialClient for NetworkDriver<'a> {
+    fn provisioned(&self, result: Result<(), ErrorCode>) {
+        if let Some(process_id) = self.provisioning.take() {
+            let _ = self.grant_access.enter(process_id, |_app_storage, upcalls_table| {
+                let _ = upcalls_table.schedule_upcall(6, (into_statuscode(result), 0, 0));
+            });
+        }
+    }
+}
+
+/// Copy a process buffer, up to its end or to a zero byte, into `out`; returns the length
+fn copy_string(source: &ReadableProcessSlice, out: &mut [u8]) -> Result<usize, ErrorCode> {
+    let len = (0..source.len()).position(|i| source[i].get() == 0).unwrap_or(source.len());
+    if len > out.len() {
+        return Err(ErrorCode::SIZE);
+    }
+    for (i, byte) in out[..len].iter_mut().enumerate() {
+        *byte = source[i].get();
+    }
+    Ok(len)
+}
+
+/// Copy an address to `out`; returns its length, or None if it does not fit
+fn copy_address(address: &ReadableProcessSlice, out: &mut [u8]) -> Option<usize> {
+    if address.len() > out.len() {
+        return None;
+    }
+    for (i, byte) in out[..address.len()].iter_mut().enumerate() {
+        *byte = address[i].get();
+    }
+    Some(address.len())
+}
+
+/// Zero terminate the `len` bytes written at the start of `buffer`; returns whether they were
+/// truncated, i.e. they do not fit into it with the terminator (no bytes need no room)
+fn zero_terminate(buffer: &WriteableProcessSlice, len: usize) -> bool {
+    if len < buffer.len() {
+        buffer[len].set(0);
+        false
+    } else {
+        len > 0
+    }
+}
+
+/// Link state reported to processes: (state, proxy version | proxy capabilities << 8,
+/// longest request accepted by the proxy)
+fn link_state_values(state: LinkState) -> (usize, usize, usize) {
+    match state {
+        LinkState::Down => (0, 0, 0),
+        LinkState::Handshaking => (1, 0, 0),
+        LinkState::Up(proxy) => {
+            let capabilities =
+                (proxy.push as usize) | (proxy.compression as usize) << 1 | (proxy.pacing as usize) << 2;
+            (2, proxy.version as usize | capabilities << 8, proxy.max_request_len)
+        }
+        LinkState::Incompatible(version) => (3, version as usize, 0),
+    }
+}
+
+impl<'a> SyscallDriver for NetworkDriver<'a> {
+    fn command(
+        &self,
+        command_num: usize,
+        r2: usize,
+        r3: usize,
+        process_id: ProcessId,
+    ) -> CommandReturn {
+        match command_num {
+            0 => CommandReturn::success(),
+            // send request
+            1 => match (Method::from_usize(r2), ContentType::from_usize(r3)) {
+                (Some(method), Some(content_type)) => {
+                    let request = Request {
+                        method,
+                        content_type,
+                        offline_tag: None,
+                    };
+                    match self.start_request(process_id, request) {
+                        Ok(()) => CommandReturn::success(),
+                        Err(err) => CommandReturn::failure(err),
+                    }
+                }
+                _ => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // set request timeout
+            2 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    app_storage.timeout_ms = r2 as u32;
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err.into()),
+                }
+            }
+            // cancel request
+            3 => match self.cancel_request(process_id) {
+                Ok(()) => CommandReturn::success(),
+                Err(err) => CommandReturn::failure(err),
+            },
+            // send POST request, stored if it cannot reach the server
+            4 => match ContentType::from_usize(r2) {
+                Some(content_type) => {
+                    let request = Request {
+                        method: Method::Post,
+                        content_type,
+                        offline_tag: Some(r3 as u32),
+                    };
+                    match self.start_request(process_id, request) {
+                        Ok(()) => CommandReturn::success(),
+                        // No proxy: the request is stored right away
+                        Err(err) if self.store_unreachable(process_id, Some(request), HttpError::Kernel(err)) => {
+                            CommandReturn::success()
+                        }
+                        Err(err) => CommandReturn::failure(err),
+                    }
+                }
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // store POST request
+            5 => match ContentType::from_usize(r2) {
+                Some(content_type) => {
+                    let res = if self.has_request(process_id) {
+                        Err(ErrorCode::ALREADY)
+                    } else {
+                        self.store_request(process_id, r3 as u32, content_type, None)
+                    };
+                    match res {
+                        Ok(()) => CommandReturn::success(),
+                        Err(err) => CommandReturn::failure(err),
+                    }
+                }
+                None => CommandReturn::failure(ErrorCode::INVAL),
+            },
+            // link state
+            6 => match self.link.map(|link| link_state_values(link.link_state())) {
+                Some((state, proxy, max_request_len)) => {
+                    CommandReturn::success_u32_u32_u32(state as u32, proxy as u32, max_request_len as u32)
+                }
+                None => CommandReturn::failure(ErrorCode::NOSUPPORT),
+            },
+            // handshake
+            7 => match self.link.map_or(Err(ErrorCode::NOSUPPORT), |link| link.handshake()) {
+                Ok(()) => CommandReturn::success(),
+                Err(err) => CommandReturn::failure(err),
+            },
+            // set progress interval
+            8 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    app_storage.progress_interval = r2;
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err.into()),
+                }
+            }
+            // stream responses
+            9 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    app_storage.streaming = r2 != 0;
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err.into()),
+                }
+            }
+            // acknowledge chunk
+            10 => {
+                let res = if self.current.contains(&process_id) && self.streaming.get() {
+                    self.http.next_segment()
+                } else {
+                    Err(ErrorCode::ALREADY)
+                };
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err),
+                }
+            }
+            // extract JSON field
+            11 => match self.extract_json(process_id) {
+                Ok((len, value_type)) => CommandReturn::success_u32_u32(len as u32, value_type as u32),
+                Err(err) => CommandReturn::failure(err),
+            },
+            // conditional GET requests
+            12 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    app_storage.conditional = r2 != 0;
+                    if !app_storage.conditional {
+                        app_storage.validator = None;
+                    }
+                });
+                match res {
+                    Ok(()) => CommandReturn::success(),
+                    Err(err) => CommandReturn::failure(err.into()),
+                }
+            }
+            // provision credential
+            PROVISION_COMMAND => match self.provision(process_id) {
+                Ok(()) => CommandReturn::success(),
+                Err(err) => CommandReturn::failure(err),
+            },
+            _ => CommandReturn::failure(ErrorCode::NOSUPPORT),
+        }
+    }
+
+    fn allow_readonly(
+        &self,
+        process_id: ProcessId,
+        allow_num: usize,
+        mut buffer: ReadOnlyProcessBuffer,
+    ) -> Result<ReadOnlyProcessBuffer, (ReadOnlyProcessBuffer, ErrorCode)> {
+        match allow_num {
+            // address
+            0 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.address, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // data_out
+            1 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.data_out, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // headers
+            2 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.headers, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // JSON document
+            3 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.json, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // JSON key path
+            4 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.json_path, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // credential origin
+            5 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.credential_origin, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // credential token
+            6 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.credential_token, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            _ => Err((buffer, ErrorCode::NOSUPPORT)),
+        }
+    }
+
+    fn allow_readwrite(
+        &self,
+        process_id: ProcessId,
+        allow_num: usize,
+        mut buffer: ReadWriteProcessBuffer,
+    ) -> Result<ReadWriteProcessBuffer, (ReadWriteProcessBuffer, ErrorCode)> {
+        match allow_num {
+            // data_in
+            0 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.data_in, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // response headers
+            1 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.response_headers, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // pushed messages
+            2 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.push_buffer, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            // JSON value
+            3 => {
+                let res = self.grant_access.enter(process_id, |app_storage, _upcalls_table| {
+                    core::mem::swap(&mut app_storage.json_value, &mut buffer);
+                });
+                match res {
+                    Ok(()) => Ok(buffer),
+                    Err(err) => Err((buffer, err.into())),
+                }
+            }
+            _ => Err((buffer, ErrorCode::NOSUPPORT)),
+        }
+    }
+
+    fn allocate_grant(&self, process_id: ProcessId) -> Result<(), Error> {
+        self.grant_access.enter(process_id, |_app_storage, _upcalls_table| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_fit_is_truncated() {
+        // No room for the terminator
+        let mut buffer = *b"abcd";
+        assert!(zero_terminate((&mut buffer[..]).into(), 4));
+        assert_eq!(&buffer, b"abcd");
+
+        let mut buffer = *b"abcd";
+        assert!(!zero_terminate((&mut buffer[..]).into(), 3));
+        assert_eq!(&buffer, b"abc\0");
+
+        // Nothing to terminate
+        assert!(!zero_terminate((&mut [][..]).into(), 0));
+    }
+}
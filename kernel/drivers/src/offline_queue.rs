@@ -0,0 +1,510 @@
+//! Offline queue of POST requests
+//!
+//! Keeps the POST requests that could not reach the server in flash, in a TicKV store
+//! (`capsules::tickv`), so that they survive a reboot. Once a later request succeeds,
+//! `flush` sends them again in the order they were stored, through the queue's own
+//! `VirtualHttpDevice`, and reports each one with `OfflineQueueClient::flushed`. A request
+//! stays in the queue only while it cannot reach the server; one that fails otherwise is
+//! dropped.
+//!
+//! The requests are kept in `QUEUE_SLOTS` slots, under the keys "netq.<slot>", as
+//!     sequence number (4 bytes, LE) | tag (4 bytes, LE) | request length (2 bytes, LE) | request
+//! where the request is the one written by the client, as sent to the proxy. The store keeps
+//! the whole buffer it is given, so a record is copied into the shortest of the store buffers
+//! (`STORE_LENS`) that it fits into and stored from there; one that fits none of them is
+//! stored from the record buffer.
+//! Removed requests keep their flash until the store is garbage collected, which is done
+//! when a request cannot be stored, before trying once more.
+//!
+//! Usage (board `main.rs`):
+//!     let offline_http = static_init!(VirtualHttpDevice<'static>, VirtualHttpDevice::new(mux_http));
+//!     offline_http.setup();
+//!     let offline_queue = static_init!(
+//!         PersistentQueue<'static, TicKVStore<...>>,
+//!         PersistentQueue::new(
+//!             tickv, offline_http, key_buffer, unhashed_key_buffer, record_buffer, store_buffer, replay_buffer
+//!         )
+//!     );
+//!     tickv.set_client(offline_queue);
+//!     offline_http.set_client(offline_queue);
+//!     offline_queue.load();
+
+use core::cell::Cell;
+
+use kernel::hil::kv_system::{self, KVSystem};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+use crate::http::{
+    ContentType, Http, HttpClient, HttpError, HttpResponse, Method, RequestWriter,
+};
+
+/// Number of requests the queue can hold
+pub const QUEUE_SLOTS: usize = 8;
+
+// Sequence number, tag and request length
+const RECORD_HEADER_LEN: usize = 10;
+
+/// Lengths of the store buffers, split from the store buffer given to `PersistentQueue::new`
+pub const STORE_LENS: [usize; 2] = [128, 256];
+pub const STORE_BUFFER_LEN: usize = STORE_LENS[0] + STORE_LENS[1];
+
+const KEY_PREFIX: &[u8] = b"netq.";
+
+/// Keeps requests until the server can be reached
+pub trait OfflineQueue<'a> {
+    fn set_client(&self, client: &'a dyn OfflineQueueClient);
+
+    /// Store a POST request; `OfflineQueueClient::write_record` is called right away to
+    /// write it and `OfflineQueueClient::stored` reports the end of the storing
+    ///
+    /// `tag` comes back with `OfflineQueueClient::flushed`. Fails with BUSY if another
+    /// request is being stored or the requests stored before the reboot are still being
+    /// found, and with NOMEM if the queue is full.
+    fn store(&self, tag: u32, content_type: ContentType) -> Result<(), ErrorCode>;
+
+    /// Send the stored requests, in order, until one of them cannot reach the server
+    fn flush(&self);
+}
+
+pub trait OfflineQueueClient {
+    /// Write the address, and optionally the extra headers and the body, of the request
+    /// being stored
+    fn write_record(&self, request: &mut RequestWriter) -> Result<(), ErrorCode>;
+
+    fn stored(&self, result: Result<(), ErrorCode>);
+
+    /// A stored request was sent, or failed otherwise than by not reaching the server, and
+    /// was removed from the queue: its tag and its result
+    fn flushed(&self, tag: u32, result: Result<HttpResponse, HttpError>);
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum QueueState {
+    // Reading the slots, at start
+    Loading(usize),
+    Idle,
+    Storing(usize),
+    // Reclaiming the flash of the removed requests, to store the request again
+    Collecting(usize),
+    // Reading the request of a slot, to send it
+    Reading(usize),
+    Sending(usize),
+    Removing(usize),
+}
+
+pub struct PersistentQueue<'a, K: KVSystem<'a>>
+where
+    K::K: 'static,
+{
+    kv: &'a K,
+    http: &'a dyn Http<'a>,
+    client: OptionalCell<&'a dyn OfflineQueueClient>,
+    state: Cell<QueueState>,
+    // Sequence numbers of the requests in the slots
+    slots: Cell<[Option<u32>; QUEUE_SLOTS]>,
+    key: TakeCell<'static, K::K>,
+    unhashed_key: TakeCell<'static, [u8]>,
+    // The request being stored
+    record: TakeCell<'static, [u8]>,
+    // The record is copied into one of them to be stored
+    store_buffers: [TakeCell<'static, [u8]>; 2],
+    // Slot and sequence number of the request waiting to be stored
+    store_pending: Cell<Option<(usize, u32)>>,
+    // The store was garbage collected for the request being stored
+    collected: Cell<bool>,
+    // The request being sent again
+    replay: TakeCell<'static, [u8]>,
+    flushing: Cell<bool>,
+}
+
+impl<'a, K: KVSystem<'a>> PersistentQueue<'a, K>
+where
+    K::K: 'static,
+{
+    pub fn new(
+        kv: &'a K,
+        http: &'a dyn Http<'a>,
+        key: &'static mut K::K,
+        unhashed_key: &'static mut [u8],
+        record: &'static mut [u8],
+        store: &'static mut [u8; STORE_BUFFER_LEN],
+        replay: &'static mut [u8],
+    ) -> PersistentQueue<'a, K> {
+        let (store_0, store_1) = store.split_at_mut(STORE_LENS[0]);
+        PersistentQueue {
+            kv,
+            http,
+            client: OptionalCell::empty(),
+            state: Cell::new(QueueState::Idle),
+            slots: Cell::new([None; QUEUE_SLOTS]),
+            key: TakeCell::new(key),
+            unhashed_key: TakeCell::new(unhashed_key),
+            record: TakeCell::new(record),
+            store_buffers: [TakeCell::new(store_0), TakeCell::new(store_1)],
+            store_pending: Cell::new(None),
+            collected: Cell::new(false),
+            replay: TakeCell::new(replay),
+            flushing: Cell::new(false),
+        }
+    }
+
+    /// Find the requests stored before the last reboot
+    pub fn load(&self) {
+        if self.state.get() == QueueState::Idle {
+            self.state.set(QueueState::Loading(0));
+            self.start_operation(0);
+        }
+    }
+
+    fn set_slot(&self, slot: usize, sequence: Option<u32>) {
+        let mut slots = self.slots.get();
+        slots[slot] = sequence;
+        self.slots.set(slots);
+    }
+
+    /// The slot of the oldest request
+    fn oldest_slot(&self) -> Option<usize> {
+        let slots = self.slots.get();
+        (0..QUEUE_SLOTS)
+            .filter(|&slot| slots[slot].is_some())
+            .min_by_key(|&slot| slots[slot])
+    }
+
+    /// Start the next operation on the store, if it is idle
+    fn run(&self) {
+        if self.state.get() != QueueState::Idle {
+            return;
+        }
+        if let Some((slot, _sequence)) = self.store_pending.get() {
+            self.state.set(QueueState::Storing(slot));
+            self.start_operation(slot);
+        } else if self.flushing.get() {
+            match self.oldest_slot() {
+                Some(slot) => {
+                    self.state.set(QueueState::Reading(slot));
+                    self.start_operation(slot);
+                }
+                None => self.flushing.set(false),
+            }
+        }
+    }
+
+    /// Generate the key of the slot; the operation of the state continues once it is ready
+    fn start_operation(&self, slot: usize) {
+        let res = match (self.unhashed_key.take(), self.key.take()) {
+            (Some(unhashed_key), Some(key)) => {
+                unhashed_key[..KEY_PREFIX.len()].copy_from_slice(KEY_PREFIX);
+                unhashed_key[KEY_PREFIX.len()] = b'0' + slot as u8;
+                self.kv.generate_key(unhashed_key, key).map_err(|(unhashed_key, key, res)| {
+                    self.unhashed_key.replace(unhashed_key);
+                    self.key.replace(key);
+                    res.err().unwrap_or(ErrorCode::FAIL)
+                })
+            }
+            (unhashed_key, key) => {
+                unhashed_key.map(|unhashed_key| self.unhashed_key.replace(unhashed_key));
+                key.map(|key| self.key.replace(key));
+                Err(ErrorCode::BUSY)
+            }
+        };
+        if let Err(error) = res {
+            self.operation_done(Err(error));
+        }
+    }
+
+    /// Continue with the next slot, report the stored request or go on flushing
+    fn operation_done(&self, result: Result<(), ErrorCode>) {
+        match self.state.get() {
+            QueueState::Loading(slot) => {
+                let sequence = match result {
+                    Ok(()) => self.replay.map(|replay| read_u32(&replay[0..4])),
+                    Err(_) => None,
+                };
+                self.set_slot(slot, sequence);
+                if slot + 1 < QUEUE_SLOTS {
+                    self.state.set(QueueState::Loading(slot + 1));
+                    self.start_operation(slot + 1);
+                    return;
+                }
+                self.state.set(QueueState::Idle);
+            }
+            QueueState::Storing(slot) => {
+                if result.is_err() && !self.collected.replace(true) && self.collect_garbage(slot) {
+                    return;
+                }
+                self.collected.set(false);
+                if let Some((_slot, sequence)) = self.store_pending.take() {
+                    if result.is_ok() {
+                        self.set_slot(slot, Some(sequence));
+                    }
+                }
+                self.state.set(QueueState::Idle);
+                self.client.map(|client| client.stored(result));
+            }
+            QueueState::Reading(slot) => {
+                if result.is_err() {
+                    // The request cannot be read back; drop it
+                    self.state.set(QueueState::Removing(slot));
+                    self.start_operation(slot);
+                    return;
+                }
+                self.state.set(QueueState::Sending(slot));
+                if let Err(error) = self.http.request(Method::Post, ContentType::Json, 0) {
+                    // Kept for the next flush if the proxy is not there (OFF), dropped otherwise
+                    self.request_done(Err(HttpError::Kernel(error)));
+                }
+                return;
+            }
+            QueueState::Removing(slot) => {
+                // Even if the key could not be invalidated, the request is not sent twice
+                // before the next reboot
+                self.set_slot(slot, None);
+                self.state.set(QueueState::Idle);
+            }
+            QueueState::Idle | QueueState::Collecting(_) | QueueState::Sending(_) => {}
+        }
+        self.run();
+    }
+
+    /// The buffer to store the record from: the shortest store buffer it fits into, with a
+    /// copy of it, or the record buffer
+    fn take_store_buffer(&self) -> Option<&'static mut [u8]> {
+        let record = self.record.take()?;
+        let len = RECORD_HEADER_LEN + read_u16(&record[8..10]) as usize;
+        let store_buffer = self
+            .store_buffers
+            .iter()
+            .find(|store_buffer| store_buffer.map_or(false, |store_buffer| store_buffer.len() >= len))
+            .and_then(|store_buffer| store_buffer.take());
+        match store_buffer {
+            Some(store_buffer) => {
+                store_buffer[..len].copy_from_slice(&record[..len]);
+                self.record.replace(record);
+                Some(store_buffer)
+            }
+            None => Some(record),
+        }
+    }
+
+    /// Put back the buffer given by `take_store_buffer`
+    fn replace_store_buffer(&self, buffer: &'static mut [u8]) {
+        if self.record.is_none() {
+            self.record.replace(buffer);
+        } else if let Some(store_buffer) = self.store_buffers.iter().find(|store_buffer| store_buffer.is_none()) {
+            store_buffer.replace(buffer);
+        }
+    }
+
+    /// Garbage collect the store, then store the request again; returns whether it started
+    fn collect_garbage(&self, slot: usize) -> bool {
+        self.state.set(QueueState::Collecting(slot));
+        if self.kv.garbage_collect().is_ok() {
+            true
+        } else {
+            self.state.set(QueueState::Storing(slot));
+            false
+        }
+    }
+}
+
+impl<'a, K: KVSystem<'a>> OfflineQueue<'a> for PersistentQueue<'a, K>
+where
+    K::K: 'static,
+{
+    fn set_client(&self, client: &'a dyn OfflineQueueClient) {
+        self.client.set(client);
+    }
+
+    fn store(&self, tag: u32, content_type: ContentType) -> Result<(), ErrorCode> {
+        if self.store_pending.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+        if let QueueState::Loading(_) = self.state.get() {
+            // The slots not read yet may hold a request
+            return Err(ErrorCode::BUSY);
+        }
+        let slots = self.slots.get();
+        let slot = (0..QUEUE_SLOTS).find(|&slot| slots[slot].is_none()).ok_or(ErrorCode::NOMEM)?;
+        let sequence = slots.iter().flatten().max().map_or(0, |sequence| sequence + 1);
+
+        let record = self.record.take().ok_or(ErrorCode::BUSY)?;
+        // The client writes the request after the record header
+        let res = {
+            let mut request = RequestWriter::new(&mut record[RECORD_HEADER_LEN..], Method::Post, content_type);
+            self.client
+                .map_or(Err(ErrorCode::FAIL), |client| client.write_record(&mut request))
+                .and_then(|()| request.finish())
+        };
+        let res = res.and_then(|len| {
+            if len > u16::MAX as usize {
+                return Err(ErrorCode::SIZE);
+            }
+            record[0..4].copy_from_slice(&sequence.to_le_bytes());
+            record[4..8].copy_from_slice(&tag.to_le_bytes());
+            record[8..10].copy_from_slice(&(len as u16).to_le_bytes());
+            Ok(())
+        });
+        self.record.replace(record);
+        res?;
+
+        self.store_pending.set(Some((slot, sequence)));
+        self.run();
+        Ok(())
+    }
+
+    fn flush(&self) {
+        self.flushing.set(true);
+        self.run();
+    }
+}
+
+impl<'a, K: KVSystem<'a>> kv_system::Client<K::K> for PersistentQueue<'a, K>
+where
+    K::K: 'static,
+{
+    fn generate_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        unhashed_key: &'static mut [u8],
+        key: &'static mut K::K,
+    ) {
+        self.unhashed_key.replace(unhashed_key);
+        if result.is_err() {
+            self.key.replace(key);
+            self.operation_done(result);
+            return;
+        }
+
+        // The operation of the state, on the key of its slot
+        let res = match self.state.get() {
+            QueueState::Loading(_) | QueueState::Reading(_) => match self.replay.take() {
+                Some(replay) => self.kv.get_value(key, replay).map_err(|(key, replay, res)| {
+                    self.key.replace(key);
+                    self.replay.replace(replay);
+                    res.err().unwrap_or(ErrorCode::FAIL)
+                }),
+                None => {
+                    self.key.replace(key);
+                    Err(ErrorCode::BUSY)
+                }
+            },
+            QueueState::Storing(_) => match self.take_store_buffer() {
+                Some(value) => self.kv.append_key(key, value).map_err(|(key, value, res)| {
+                    self.key.replace(key);
+                    self.replace_store_buffer(value);
+                    res.err().unwrap_or(ErrorCode::FAIL)
+                }),
+                None => {
+                    self.key.replace(key);
+                    Err(ErrorCode::BUSY)
+                }
+            },
+            QueueState::Removing(_) => self.kv.invalidate_key(key).map_err(|(key, res)| {
+                self.key.replace(key);
+                res.err().unwrap_or(ErrorCode::FAIL)
+            }),
+            QueueState::Idle | QueueState::Collecting(_) | QueueState::Sending(_) => {
+                self.key.replace(key);
+                Ok(())
+            }
+        };
+        if let Err(error) = res {
+            self.operation_done(Err(error));
+        }
+    }
+
+    fn append_key_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut K::K,
+        value: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        self.replace_store_buffer(value);
+        self.operation_done(result);
+    }
+
+    fn get_value_complete(
+        &self,
+        result: Result<(), ErrorCode>,
+        key: &'static mut K::K,
+        ret_buf: &'static mut [u8],
+    ) {
+        self.key.replace(key);
+        // Check that the record is whole
+        let result = result.and_then(|()| {
+            let len = read_u16(&ret_buf[8..10]) as usize;
+            if RECORD_HEADER_LEN + len <= ret_buf.len() {
+                Ok(())
+            } else {
+                Err(ErrorCode::FAIL)
+            }
+        });
+        self.replay.replace(ret_buf);
+        self.operation_done(result);
+    }
+
+    fn invalidate_key_complete(&self, result: Result<(), ErrorCode>, key: &'static mut K::K) {
+        self.key.replace(key);
+        self.operation_done(result);
+    }
+
+    fn garbage_collect_complete(&self, _result: Result<(), ErrorCode>) {
+        if let QueueState::Collecting(slot) = self.state.get() {
+            // Once more, even if nothing was reclaimed
+            self.state.set(QueueState::Storing(slot));
+            self.start_operation(slot);
+        }
+    }
+}
+
+impl<'a, K: KVSystem<'a>> HttpClient for PersistentQueue<'a, K>
+where
+    K::K: 'static,
+{
+    fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+        self.replay
+            .map(|replay| {
+                let len = read_u16(&replay[8..10]) as usize;
+                request.write_rendered(&replay[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len])
+            })
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn response_headers(&self, _offset: usize, _data: &[u8]) {}
+
+    fn response_body(&self, _offset: usize, _data: &[u8]) {}
+
+    fn request_done(&self, result: Result<HttpResponse, HttpError>) {
+        let slot = match self.state.get() {
+            QueueState::Sending(slot) => slot,
+            _ => return,
+        };
+
+        match result {
+            Err(error) if error.is_unreachable() => {
+                // Still offline; keep the request for the next flush
+                self.flushing.set(false);
+                self.state.set(QueueState::Idle);
+                self.run();
+            }
+            _ => {
+                // The server got the request, or sending it again would fail the same way
+                // (e.g. SIZE) and hold up the requests stored after it
+                let tag = self.replay.map_or(0, |replay| read_u32(&replay[4..8]));
+                self.client.map(|client| client.flushed(tag, result));
+                self.state.set(QueueState::Removing(slot));
+                self.start_operation(slot);
+            }
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -0,0 +1,448 @@
+//! Channels over a serial link
+//!
+//! Splits one UART into independent byte streams, so that the console, the kernel debug
+//! output and the network traffic share it without ever interleaving. Every transmission
+//! is sent in channel frames:
+//!     0xC5 | channel ID (1 byte) | payload length (2 bytes, LE) | payload
+//! and the bytes received in channel frames go to the channel with that ID. The proxy on
+//! the host (applications/network_adapter.py) splits and frames the streams the same way.
+//! Bytes received while their channel has no receive pending are dropped, as on a UART.
+//!
+//! Each `SerialChannel` is a `Uart`, so it can be given to `UartMuxComponent` in place of
+//! the hardware UART, or to `UartTransport` (board `main.rs`):
+//!     let serial_channels = static_init!(
+//!         SerialChannels<'static>,
+//!         SerialChannels::new(&uart, tx_buffer, rx_buffer, dynamic_deferred_caller)
+//!     );
+//!     serial_channels.initialize_callback_handle(dynamic_deferred_caller.register(serial_channels).unwrap());
+//!     uart.set_transmit_client(serial_channels);
+//!     uart.set_receive_client(serial_channels);
+//!     let network_channel = static_init!(SerialChannel<'static>, SerialChannel::new(serial_channels, CHANNEL_NETWORK));
+//!     network_channel.setup();
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::uart::{
+    Configure, Error, Parameters, Receive, ReceiveClient, Transmit, TransmitClient, Uart,
+};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Console output and input of the processes
+pub const CHANNEL_CONSOLE: u8 = 0;
+/// Kernel debug output (`debug!`)
+pub const CHANNEL_DEBUG: u8 = 1;
+/// Frames of the network driver
+pub const CHANNEL_NETWORK: u8 = 2;
+
+const CHANNEL_SYNC: u8 = 0xC5;
+// Sync, channel ID and payload length
+const CHANNEL_HEADER_LEN: usize = 4;
+const CHANNEL_MAX_PAYLOAD_LEN: usize = 0xFFFF;
+
+#[derive(Copy, Clone, PartialEq)]
+enum RxState {
+    Sync,
+    Channel,
+    // Bytes of the payload length received so far
+    Length(usize),
+    // Bytes of the payload still to be received
+    Payload(usize),
+}
+
+pub struct SerialChannels<'a> {
+    uart: &'a dyn Uart<'a>,
+    channels: List<'a, SerialChannel<'a>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    // Channel whose frame is being transmitted
+    tx_channel: OptionalCell<&'a SerialChannel<'a>>,
+    // Channel that transmitted the latest frame, for taking turns between channels
+    last_tx_id: Cell<u8>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    receiving: Cell<bool>,
+    rx_state: Cell<RxState>,
+    rx_channel: Cell<u8>,
+    rx_len: Cell<usize>,
+}
+
+impl<'a> SerialChannels<'a> {
+    pub fn new(
+        uart: &'a dyn Uart<'a>,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> SerialChannels<'a> {
+        SerialChannels {
+            uart,
+            channels: List::new(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+            tx_buffer: TakeCell::new(tx_buffer),
+            tx_channel: OptionalCell::empty(),
+            last_tx_id: Cell::new(0),
+            rx_buffer: TakeCell::new(rx_buffer),
+            receiving: Cell::new(false),
+            rx_state: Cell::new(RxState::Sync),
+            rx_channel: Cell::new(0),
+            rx_len: Cell::new(0),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    fn channel(&self, id: u8) -> Option<&'a SerialChannel<'a>> {
+        self.channels.iter().find(|channel| channel.id == id)
+    }
+
+    /// The first channel with a pending transmission after the one that transmitted the
+    /// latest frame
+    fn next_tx_channel(&self) -> Option<&'a SerialChannel<'a>> {
+        let last_tx_id = self.last_tx_id.get();
+        let mut first: Option<&'a SerialChannel<'a>> = None;
+        for channel in self.channels.iter().filter(|channel| channel.tx_buffer.is_some()) {
+            if channel.id > last_tx_id {
+                return Some(channel);
+            }
+            if first.map_or(true, |first| channel.id < first.id) {
+                first = Some(channel);
+            }
+        }
+        first
+    }
+
+    /// Transmit the next frame, unless one is being transmitted
+    ///
+    /// If the UART refuses the first frame of the channel `queued`, its buffer is returned
+    /// with the error instead of going to its client, as the channel is still in
+    /// `transmit_buffer`.
+    fn transmit_next(&self, queued: Option<u8>) -> Option<(ErrorCode, &'static mut [u8])> {
+        let mut refused = None;
+        while self.tx_channel.is_none() {
+            let channel = match self.next_tx_channel() {
+                Some(channel) => channel,
+                None => break,
+            };
+            let tx_buffer = match self.tx_buffer.take() {
+                Some(tx_buffer) => tx_buffer,
+                None => break,
+            };
+
+            // Frame the next part of the transmission of the channel
+            let len = channel
+                .tx_buffer
+                .map(|data| {
+                    let sent = channel.tx_sent.get();
+                    let len = core::cmp::min(
+                        channel.tx_len.get() - sent,
+                        core::cmp::min(tx_buffer.len() - CHANNEL_HEADER_LEN, CHANNEL_MAX_PAYLOAD_LEN),
+                    );
+                    tx_buffer[0] = CHANNEL_SYNC;
+                    tx_buffer[1] = channel.id;
+                    tx_buffer[2..CHANNEL_HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+                    tx_buffer[CHANNEL_HEADER_LEN..CHANNEL_HEADER_LEN + len]
+                        .copy_from_slice(&data[sent..sent + len]);
+                    len
+                })
+                .unwrap_or(0);
+
+            self.last_tx_id.set(channel.id);
+            match self.uart.transmit_buffer(tx_buffer, CHANNEL_HEADER_LEN + len) {
+                Ok(()) => {
+                    channel.tx_frame_len.set(len);
+                    self.tx_channel.set(channel);
+                }
+                Err((error, tx_buffer)) => {
+                    self.tx_buffer.replace(tx_buffer);
+                    if queued == Some(channel.id) {
+                        refused = channel.tx_buffer.take().map(|tx_buffer| (error, tx_buffer));
+                    } else {
+                        channel.transmitted(Err(error));
+                    }
+                }
+            }
+        }
+        refused
+    }
+
+    /// Keep reading from the UART once a channel is receiving
+    fn receive_next(&self) {
+        if self.receiving.get() {
+            return;
+        }
+        if let Some(rx_buffer) = self.rx_buffer.take() {
+            let expected_len = match self.rx_state.get() {
+                RxState::Sync => 1,
+                RxState::Channel => CHANNEL_HEADER_LEN - 1,
+                RxState::Length(i) => 2 - i,
+                RxState::Payload(remaining) => remaining,
+            };
+            let len = core::cmp::min(expected_len, rx_buffer.len());
+            match self.uart.receive_buffer(rx_buffer, len) {
+                Ok(()) => self.receiving.set(true),
+                Err((_error, rx_buffer)) => {
+                    self.rx_buffer.replace(rx_buffer);
+                }
+            }
+        }
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        match self.rx_state.get() {
+            RxState::Sync => {
+                if byte == CHANNEL_SYNC {
+                    self.rx_state.set(RxState::Channel);
+                }
+            }
+            RxState::Channel => {
+                self.rx_channel.set(byte);
+                self.rx_len.set(0);
+                self.rx_state.set(RxState::Length(0));
+            }
+            RxState::Length(i) => {
+                self.rx_len.set(self.rx_len.get() | ((byte as usize) << (8 * i)));
+                if i == 0 {
+                    self.rx_state.set(RxState::Length(1));
+                } else if self.rx_len.get() > 0 {
+                    self.rx_state.set(RxState::Payload(self.rx_len.get()));
+                } else {
+                    self.rx_state.set(RxState::Sync);
+                }
+            }
+            RxState::Payload(remaining) => {
+                self.rx_state.set(if remaining > 1 { RxState::Payload(remaining - 1) } else { RxState::Sync });
+                if let Some(channel) = self.channel(self.rx_channel.get()) {
+                    channel.receive_byte(byte);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> TransmitClient for SerialChannels<'a> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.tx_buffer.replace(tx_buffer);
+        if let Some(channel) = self.tx_channel.take() {
+            channel.tx_sent.set(channel.tx_sent.get() + channel.tx_frame_len.get());
+            if rval.is_err() || channel.tx_sent.get() == channel.tx_len.get() {
+                channel.transmitted(rval);
+            }
+        }
+        let _ = self.transmit_next(None);
+    }
+}
+
+impl<'a> ReceiveClient for SerialChannels<'a> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: Error,
+    ) {
+        self.receiving.set(false);
+        match rval {
+            Ok(()) => {
+                for &byte in rx_buffer[..core::cmp::min(rx_len, rx_buffer.len())].iter() {
+                    self.receive_byte(byte);
+                }
+            }
+            Err(_) => {
+                // Bytes were lost; hunt for the start of the next frame
+                self.rx_state.set(RxState::Sync);
+            }
+        }
+        self.rx_buffer.replace(rx_buffer);
+        self.receive_next();
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SerialChannels<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        for channel in self.channels.iter() {
+            if channel.rx_aborted.get() {
+                channel.rx_aborted.set(false);
+                if let Some(rx_buffer) = channel.rx_buffer.take() {
+                    let rx_received = channel.rx_received.get();
+                    channel.rx_client.map(move |client| {
+                        client.received_buffer(rx_buffer, rx_received, Err(ErrorCode::CANCEL), Error::Aborted)
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// One byte stream over the serial link
+pub struct SerialChannel<'a> {
+    mux: &'a SerialChannels<'a>,
+    id: u8,
+    next: ListLink<'a, SerialChannel<'a>>,
+    tx_client: OptionalCell<&'a dyn TransmitClient>,
+    rx_client: OptionalCell<&'a dyn ReceiveClient>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_sent: Cell<usize>,
+    // Length of the payload of the frame being transmitted
+    tx_frame_len: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_received: Cell<usize>,
+    rx_aborted: Cell<bool>,
+}
+
+impl<'a> SerialChannel<'a> {
+    pub fn new(mux: &'a SerialChannels<'a>, id: u8) -> SerialChannel<'a> {
+        SerialChannel {
+            mux,
+            id,
+            next: ListLink::empty(),
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_sent: Cell::new(0),
+            tx_frame_len: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_received: Cell::new(0),
+            rx_aborted: Cell::new(false),
+        }
+    }
+
+    /// Add the channel to the serial link
+    pub fn setup(&'a self) {
+        self.mux.channels.push_tail(self);
+    }
+
+    /// Give back the transmitted buffer
+    fn transmitted(&self, rval: Result<(), ErrorCode>) {
+        if let Some(tx_buffer) = self.tx_buffer.take() {
+            let tx_len = self.tx_len.get();
+            self.tx_client.map(move |client| client.transmitted_buffer(tx_buffer, tx_len, rval));
+        }
+    }
+
+    fn receive_byte(&self, byte: u8) {
+        let done = self.rx_buffer.map(|rx_buffer| {
+            let rx_received = self.rx_received.get();
+            rx_buffer[rx_received] = byte;
+            self.rx_received.set(rx_received + 1);
+            rx_received + 1 == self.rx_len.get()
+        });
+
+        if done == Some(true) {
+            if let Some(rx_buffer) = self.rx_buffer.take() {
+                let rx_len = self.rx_len.get();
+                self.rx_aborted.set(false);
+                self.rx_client
+                    .map(move |client| client.received_buffer(rx_buffer, rx_len, Ok(()), Error::None));
+            }
+        }
+    }
+}
+
+impl<'a> ListNode<'a, SerialChannel<'a>> for SerialChannel<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, SerialChannel<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> Configure for SerialChannel<'a> {
+    fn configure(&self, params: Parameters) -> Result<(), ErrorCode> {
+        // All the channels share the settings of the UART
+        self.mux.uart.configure(params)
+    }
+}
+
+impl<'a> Transmit<'a> for SerialChannel<'a> {
+    fn set_transmit_client(&self, client: &'a dyn TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        if tx_len == 0 || tx_len > tx_buffer.len() {
+            return Err((ErrorCode::SIZE, tx_buffer));
+        }
+
+        self.tx_buffer.replace(tx_buffer);
+        self.tx_len.set(tx_len);
+        self.tx_sent.set(0);
+        match self.mux.transmit_next(Some(self.id)) {
+            Some(refused) => Err(refused),
+            None => Ok(()),
+        }
+    }
+
+    fn transmit_word(&self, _word: u32) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        // The transmission completes normally
+        Err(ErrorCode::FAIL)
+    }
+}
+
+impl<'a> Receive<'a> for SerialChannel<'a> {
+    fn set_receive_client(&self, client: &'a dyn ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        if rx_len == 0 || rx_len > rx_buffer.len() {
+            return Err((ErrorCode::SIZE, rx_buffer));
+        }
+
+        self.rx_buffer.replace(rx_buffer);
+        self.rx_len.set(rx_len);
+        self.rx_received.set(0);
+        self.rx_aborted.set(false);
+        self.mux.receive_next();
+        Ok(())
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        Err(ErrorCode::FAIL)
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if self.rx_buffer.is_none() {
+            return Err(ErrorCode::FAIL);
+        }
+        // The buffer is given back from a deferred call, with the bytes received so far
+        self.rx_aborted.set(true);
+        self.mux.schedule();
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Byte transports between the Network driver and the proxy
+//!
+//! The Network driver frames the requests and the responses and moves their bytes
+//! through a `Transport`:
+//!     `UartTransport` -> any UART: a channel of the console UART (see
+//!                        `crate::serial_channels`), a dedicated UART or USB CDC
+//!     `LoopbackTransport` -> keeps the transmitted bytes in memory and receives bytes
+//!                            given beforehand; for exercising the driver without a proxy
+//!
+//! The board chooses the transport in `main.rs`, e.g.
+//!     let network_channel = static_init!(SerialChannel<'static>, SerialChannel::new(serial_channels, CHANNEL_NETWORK));
+//!     network_channel.setup();
+//!     let network_transport = static_init!(UartTransport<'static>, UartTransport::new(network_channel));
+//!     network_channel.set_transmit_client(network_transport);
+//!     network_channel.set_receive_client(network_transport);
+//!     ...
+//!     network_transport.set_client(network);
+
+use core::cell::Cell;
+
+use kernel::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::uart::{ReceiveClient, TransmitClient, UartData};
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+/// Moves bytes to and from the proxy
+pub trait Transport<'a> {
+    fn set_client(&self, client: &'a dyn TransportClient);
+
+    /// Transmit the first `len` bytes of `buffer`; the buffer is given back with
+    /// `TransportClient::transmitted`
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+
+    /// Receive exactly `len` bytes into `buffer`; the buffer is given back with
+    /// `TransportClient::received`
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])>;
+}
+
+pub trait TransportClient {
+    fn transmitted(&self, buffer: &'static mut [u8], len: usize, rval: Result<(), ErrorCode>);
+    fn received(&self, buffer: &'static mut [u8], len: usize, rval: Result<(), ErrorCode>);
+}
+
+/// Transport over a UART
+pub struct UartTransport<'a> {
+    uart: &'a dyn UartData<'a>,
+    client: OptionalCell<&'a dyn TransportClient>,
+}
+
+impl<'a> UartTransport<'a> {
+    pub fn new(uart: &'a dyn UartData<'a>) -> UartTransport<'a> {
+        UartTransport {
+            uart,
+            client: OptionalCell::empty(),
+        }
+    }
+}
+
+impl<'a> Transport<'a> for UartTransport<'a> {
+    fn set_client(&self, client: &'a dyn TransportClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.uart.transmit_buffer(buffer, len)
+    }
+
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        self.uart.receive_buffer(buffer, len)
+    }
+}
+
+impl<'a> TransmitClient for UartTransport<'a> {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+        rval: Result<(), ErrorCode>,
+    ) {
+        self.client.map(move |client| client.transmitted(tx_buffer, tx_len, rval));
+    }
+}
+
+impl<'a> ReceiveClient for UartTransport<'a> {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rval: Result<(), ErrorCode>,
+        _error: kernel::hil::uart::Error,
+    ) {
+        self.client.map(move |client| client.received(rx_buffer, rx_len, rval));
+    }
+}
+
+/// In-memory transport
+///
+/// The transmitted bytes are kept in the `transmitted` buffer and the bytes given with
+/// `set_response` are received. The operations complete from a deferred call, as they
+/// would on a real transport.
+///
+/// The Network driver sends requests once the proxy answered its handshake, so the first
+/// bytes given are a hello frame (see `crate::network`).
+pub struct LoopbackTransport<'a> {
+    client: OptionalCell<&'a dyn TransportClient>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+    // Copy of the latest transmitted bytes
+    transmitted: TakeCell<'static, [u8]>,
+    transmitted_len: Cell<usize>,
+    // Bytes that are still to be received
+    response: Cell<&'static [u8]>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+}
+
+impl<'a> LoopbackTransport<'a> {
+    pub fn new(
+        deferred_caller: &'a DynamicDeferredCall,
+        transmitted: &'static mut [u8],
+    ) -> LoopbackTransport<'a> {
+        LoopbackTransport {
+            client: OptionalCell::empty(),
+            deferred_caller,
+            handle: OptionalCell::empty(),
+            transmitted: TakeCell::new(transmitted),
+            transmitted_len: Cell::new(0),
+            response: Cell::new(&[]),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Set the bytes to be received next, e.g. a response frame
+    pub fn set_response(&self, response: &'static [u8]) {
+        self.response.set(response);
+        self.schedule();
+    }
+
+    /// Inspect the latest transmitted bytes (truncated to the size of the `transmitted` buffer)
+    pub fn transmitted_data<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> Option<R> {
+        let len = self.transmitted_len.get();
+        self.transmitted.map(|transmitted| f(&transmitted[..len]))
+    }
+
+    fn schedule(&self) {
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+}
+
+impl<'a> Transport<'a> for LoopbackTransport<'a> {
+    fn set_client(&self, client: &'a dyn TransportClient) {
+        self.client.set(client);
+    }
+
+    fn transmit(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.tx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        self.transmitted.map(|transmitted| {
+            let copied_len = core::cmp::min(len, transmitted.len());
+            transmitted[..copied_len].copy_from_slice(&buffer[..copied_len]);
+            self.transmitted_len.set(copied_len);
+        });
+        self.tx_buffer.replace(buffer);
+        self.tx_len.set(len);
+        self.schedule();
+        Ok(())
+    }
+
+    fn receive(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx_buffer.is_some() {
+            return Err((ErrorCode::BUSY, buffer));
+        }
+        if len > buffer.len() {
+            return Err((ErrorCode::SIZE, buffer));
+        }
+
+        self.rx_buffer.replace(buffer);
+        self.rx_len.set(len);
+        self.schedule();
+        Ok(())
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for LoopbackTransport<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let Some(tx_buffer) = self.tx_buffer.take() {
+            let tx_len = self.tx_len.get();
+            self.client.map(move |client| client.transmitted(tx_buffer, tx_len, Ok(())));
+        }
+
+        let response = self.response.get();
+        let rx_len = self.rx_len.get();
+        if self.rx_buffer.is_some() && response.len() >= rx_len {
+            // Deliver the requested bytes once they are all available
+            if let Some(rx_buffer) = self.rx_buffer.take() {
+                rx_buffer[..rx_len].copy_from_slice(&response[..rx_len]);
+                self.response.set(&response[rx_len..]);
+                self.client.map(move |client| client.received(rx_buffer, rx_len, Ok(())));
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Virtualizer for the `Http` interface
+//!
+//! Each user of the network gets a `VirtualHttpDevice`; `MuxHttp` sends their requests
+//! one at a time, taking the devices with waiting requests in turn.
+//!
+//! Usage (board `main.rs`):
+//!     let mux_http = static_init!(MuxHttp<'static>, MuxHttp::new(network));
+//!     network.set_client(mux_http);
+//!     let http_device = static_init!(VirtualHttpDevice<'static>, VirtualHttpDevice::new(mux_http));
+//!     http_device.setup();
+//!     http_device.set_client(capsule);
+
+use core::cell::Cell;
+
+use kernel::collections::list::{List, ListLink, ListNode};
+use kernel::utilities::cells::OptionalCell;
+use kernel::ErrorCode;
+
+use crate::http::{
+    ContentType, Http, HttpClient, HttpError, HttpResponse, Method, RequestWriter,
+};
+
+#[derive(Copy, Clone)]
+struct Request {
+    method: Method,
+    content_type: ContentType,
+    timeout_ms: u32,
+}
+
+pub struct MuxHttp<'a> {
+    http: &'a dyn Http<'a>,
+    devices: List<'a, VirtualHttpDevice<'a>>,
+    // Position in the list of the device that sent the latest request
+    last_index: Cell<usize>,
+}
+
+impl<'a> MuxHttp<'a> {
+    pub fn new(http: &'a dyn Http<'a>) -> MuxHttp<'a> {
+        MuxHttp {
+            http,
+            devices: List::new(),
+            last_index: Cell::new(0),
+        }
+    }
+
+    /// The device whose request is being sent
+    fn inflight(&self) -> Option<&'a VirtualHttpDevice<'a>> {
+        self.devices.iter().find(|device| device.inflight.get())
+    }
+
+    /// The first device with a waiting request after the one that sent the latest request
+    fn next_waiting(&self) -> Option<(usize, &'a VirtualHttpDevice<'a>)> {
+        let last_index = self.last_index.get();
+        let mut first = None;
+        for (index, device) in self.devices.iter().enumerate() {
+            if device.waiting.get().is_some() {
+                if index > last_index {
+                    return Some((index, device));
+                }
+                if first.is_none() {
+                    first = Some((index, device));
+                }
+            }
+        }
+        first
+    }
+
+    /// Send the request of the device at `index`
+    fn send(&self, index: usize, device: &VirtualHttpDevice<'a>, request: Request) -> Result<(), ErrorCode> {
+        device.inflight.set(true);
+        self.last_index.set(index);
+        let res = self.http.request(request.method, request.content_type, request.timeout_ms);
+        if res.is_err() {
+            device.inflight.set(false);
+        }
+        res
+    }
+
+    /// Send the waiting requests until one is ongoing
+    fn do_next_request(&self) {
+        while self.inflight().is_none() {
+            let (index, device) = match self.next_waiting() {
+                Some(next) => next,
+                None => break,
+            };
+            if let Some(request) = device.waiting.take() {
+                if let Err(error) = self.send(index, device, request) {
+                    device.client.map(|client| client.request_done(Err(HttpError::Kernel(error))));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> HttpClient for MuxHttp<'a> {
+    fn write_request(&self, request: &mut RequestWriter) -> Result<(), ErrorCode> {
+        self.inflight()
+            .and_then(|device| device.client.map(|client| client.write_request(request)))
+            .unwrap_or(Err(ErrorCode::FAIL))
+    }
+
+    fn response_headers(&self, offset: usize, data: &[u8]) {
+        if let Some(device) = self.inflight() {
+            device.client.map(|client| client.response_headers(offset, data));
+        }
+    }
+
+    fn response_body(&self, offset: usize, data: &[u8]) {
+        if let Some(device) = self.inflight() {
+            device.client.map(|client| client.response_body(offset, data));
+        }
+    }
+
+    fn response_progress(&self, received: usize, total: usize) {
+        if let Some(device) = self.inflight() {
+            device.client.map(|client| client.response_progress(received, total));
+        }
+    }
+
+    fn segment_done(&self) -> bool {
+        self.inflight()
+            .and_then(|device| device.client.map(|client| client.segment_done()))
+            .unwrap_or(true)
+    }
+
+    fn request_done(&self, result: Result<HttpResponse, HttpError>) {
+        let device = self.inflight();
+        if let Some(device) = device {
+            device.inflight.set(false);
+        }
+
+        // The other devices go first, even if this one sends another request from its callback
+        self.do_next_request();
+        if let Some(device) = device {
+            device.client.map(|client| client.request_done(result));
+        }
+    }
+}
+
+pub struct VirtualHttpDevice<'a> {
+    mux: &'a MuxHttp<'a>,
+    next: ListLink<'a, VirtualHttpDevice<'a>>,
+    client: OptionalCell<&'a dyn HttpClient>,
+    // Request waiting for the network
+    waiting: Cell<Option<Request>>,
+    inflight: Cell<bool>,
+}
+
+impl<'a> VirtualHttpDevice<'a> {
+    pub fn new(mux: &'a MuxHttp<'a>) -> VirtualHttpDevice<'a> {
+        VirtualHttpDevice {
+            mux,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            waiting: Cell::new(None),
+            inflight: Cell::new(false),
+        }
+    }
+
+    /// Add the device to the mux
+    pub fn setup(&'a self) {
+        self.mux.devices.push_tail(self);
+    }
+}
+
+impl<'a> ListNode<'a, VirtualHttpDevice<'a>> for VirtualHttpDevice<'a> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualHttpDevice<'a>> {
+        &self.next
+    }
+}
+
+impl<'a> Http<'a> for VirtualHttpDevice<'a> {
+    fn set_client(&self, client: &'a dyn HttpClient) {
+        self.client.set(client);
+    }
+
+    fn request(&self, method: Method, content_type: ContentType, timeout_ms: u32) -> Result<(), ErrorCode> {
+        if self.inflight.get() || self.waiting.get().is_some() {
+            return Err(ErrorCode::BUSY);
+        }
+
+        let request = Request {
+            method,
+            content_type,
+            timeout_ms,
+        };
+        if self.mux.inflight().is_some() {
+            // Wait for the ongoing request of another device
+            self.waiting.set(Some(request));
+            return Ok(());
+        }
+        let index = self
+            .mux
+            .devices
+            .iter()
+            .position(|device| core::ptr::eq(device, self))
+            .ok_or(ErrorCode::OFF)?;
+        self.mux.send(index, self, request)
+    }
+
+    fn cancel(&self) -> Result<bool, ErrorCode> {
+        if self.inflight.get() {
+            self.mux.http.cancel()
+        } else if self.waiting.take().is_some() {
+            Ok(false)
+        } else {
+            Err(ErrorCode::ALREADY)
+        }
+    }
+
+    fn next_segment(&self) -> Result<(), ErrorCode> {
+        if self.inflight.get() {
+            self.mux.http.next_segment()
+        } else {
+            Err(ErrorCode::ALREADY)
+        }
+    }
+}
@@ -11,12 +11,20 @@
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::hil::flash::HasClient;
+use kernel::hil::gpio::{Configure, Input};
+use kernel::hil::hasher::Hasher;
+use kernel::hil::kv_system::KVSystem;
 use kernel::hil::time::Counter;
 use kernel::hil::uart::{Receive, Transmit};
 use kernel::platform::{KernelResources, SyscallDriverLookup};
 use kernel::scheduler::round_robin::RoundRobinSched;
 
-use capsules::virtual_uart::UartDevice;
+use drivers::credential::CredentialStore;
+use drivers::http::Http;
+use drivers::network::ProxyLink;
+use drivers::offline_queue::OfflineQueue;
+use drivers::transport::Transport;
 
 #[allow(unused_imports)]
 use kernel::{create_capability, debug, debug_gpio, debug_verbose, static_init};
@@ -69,6 +77,10 @@ const FAULT_RESPONSE: kernel::process::PanicFaultPolicy = kernel::process::Panic
 const NUM_PROCS: usize = 4;
 const NUM_UPCALLS_IPC: usize = NUM_PROCS + 1;
 
+// The only process allowed to provision the API credential of the network driver, and only
+// while button A was held at reset (provisioning mode), since any app can declare this name
+const PROVISIONING_PROCESS: &str = "credential_provisioner";
+
 static mut PROCESSES: [Option<&'static dyn kernel::process::Process>; NUM_PROCS] =
     [None; NUM_PROCS];
 
@@ -113,7 +125,8 @@ pub struct MicroBit {
     app_flash: &'static capsules::app_flash_driver::AppFlash<'static>,
     sound_pressure: &'static capsules::sound_pressure::SoundPressureSensor<'static>,
 
-    network: &'static drivers::network::Network<'static>,
+    network: &'static drivers::network_driver::NetworkDriver<'static>,
+    provisioning_filter: drivers::credential::ProvisioningFilter,
 
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm4::systick::SysTick,
@@ -145,7 +158,7 @@ impl SyscallDriverLookup for MicroBit {
             capsules::buzzer_driver::DRIVER_NUM => f(Some(self.buzzer)),
             capsules::app_flash_driver::DRIVER_NUM => f(Some(self.app_flash)),
             capsules::sound_pressure::DRIVER_NUM => f(Some(self.sound_pressure)),
-            drivers::network::DRIVER_NUM => f(Some(self.network)),
+            drivers::network_driver::DRIVER_NUM => f(Some(self.network)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             drivers::led_matrix_text::DRIVER_NUM => f(Some(self.led_matrix_text)),
             _ => f(None),
@@ -157,7 +170,7 @@ impl KernelResources<nrf52833::chip::NRF52<'static, Nrf52833DefaultPeripherals<'
     for MicroBit
 {
     type SyscallDriverLookup = Self;
-    type SyscallFilter = ();
+    type SyscallFilter = drivers::credential::ProvisioningFilter;
     type ProcessFault = ();
     type Scheduler = RoundRobinSched<'static>;
     type SchedulerTimer = cortexm4::systick::SysTick;
@@ -167,7 +180,7 @@ impl KernelResources<nrf52833::chip::NRF52<'static, Nrf52833DefaultPeripherals<'
         &self
     }
     fn syscall_filter(&self) -> &Self::SyscallFilter {
-        &()
+        &self.provisioning_filter
     }
     fn process_fault(&self) -> &Self::ProcessFault {
         &()
@@ -282,12 +295,17 @@ pub unsafe fn main() {
     )
     .finalize(components::button_component_buf!(nrf52833::gpio::GPIOPin));
 
+    // Provisioning mode: button A (active low) held while the board starts
+    let button_a = &nrf52833_peripherals.gpio_port[BUTTON_A];
+    button_a.make_input();
+    let provisioning = !button_a.read();
+
     //--------------------------------------------------------------------------
     // Deferred Call (Dynamic) Setup
     //--------------------------------------------------------------------------
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 3], Default::default());
+        static_init!([DynamicDeferredCallClientState; 6], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -361,9 +379,43 @@ pub unsafe fn main() {
         None,
     );
 
-    // Create a shared UART channel for the console and for kernel debug.
+    // Split the base_peripherals.uarte0 into channels, so that the console, the kernel debug output and the
+    // network traffic cannot interleave (see `drivers::serial_channels`).
+    let serial_channels_tx_buffer = static_init!([u8; 256], [0; 256]);
+    let serial_channels_rx_buffer = static_init!([u8; 256], [0; 256]);
+    let serial_channels = static_init!(
+        drivers::serial_channels::SerialChannels<'static>,
+        drivers::serial_channels::SerialChannels::new(
+            &base_peripherals.uarte0,
+            serial_channels_tx_buffer,
+            serial_channels_rx_buffer,
+            dynamic_deferred_caller
+        )
+    );
+    serial_channels.initialize_callback_handle(dynamic_deferred_caller.register(serial_channels).unwrap());
+    base_peripherals.uarte0.set_transmit_client(serial_channels);
+    base_peripherals.uarte0.set_receive_client(serial_channels);
+
+    let console_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_CONSOLE
+        )
+    );
+    console_channel.setup();
+    let debug_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_DEBUG
+        )
+    );
+    debug_channel.setup();
+
+    // Create a UART mux for the console on its channel.
     let uart_mux = components::console::UartMuxComponent::new(
-        &base_peripherals.uarte0,
+        console_channel,
         115200,
         dynamic_deferred_caller,
     )
@@ -376,8 +428,23 @@ pub unsafe fn main() {
         uart_mux,
     )
     .finalize(());
-    // Create the debugger object that handles calls to `debug!()`.
-    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+
+    // Create the debugger object that handles calls to `debug!()`, on its own channel.
+    let debug_uart_mux_buffer = static_init!([u8; 64], [0; 64]);
+    let debug_uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            debug_channel,
+            debug_uart_mux_buffer,
+            115200,
+            dynamic_deferred_caller
+        )
+    );
+    debug_uart_mux.initialize_callback_handle(dynamic_deferred_caller.register(debug_uart_mux).unwrap());
+    debug_uart_mux.initialize();
+    debug_channel.set_transmit_client(debug_uart_mux);
+    debug_channel.set_receive_client(debug_uart_mux);
+    components::debug_writer::DebugWriterComponent::new(debug_uart_mux).finalize(());
 
     //--------------------------------------------------------------------------
     // RANDOM NUMBERS
@@ -577,21 +644,174 @@ pub unsafe fn main() {
     //         .finalize(());
     // let _ = process_console.start();
 
-    let network_grant =
-        board_kernel.create_grant(drivers::network::DRIVER_NUM, &memory_allocation_capability);
+    let network_grant = board_kernel.create_grant(
+        drivers::network_driver::DRIVER_NUM,
+        &memory_allocation_capability,
+    );
 
-    let network_uart = static_init!(UartDevice<'static>, UartDevice::new(uart_mux, true));
-    network_uart.setup();
+    // Transport to the proxy: the network channel of the UART. A dedicated UART or USB CDC
+    // can be given to `UartTransport` instead.
+    let network_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_NETWORK
+        )
+    );
+    network_channel.setup();
+    let network_transport = static_init!(
+        drivers::transport::UartTransport<'static>,
+        drivers::transport::UartTransport::new(network_channel)
+    );
+    network_channel.set_transmit_client(network_transport);
+    network_channel.set_receive_client(network_transport);
 
     let network_buffer = static_init!([u8; 1024], [0; 1024]);
+    let network_rx_buffer = static_init!([u8; 1024], [0; 1024]);
+
+    let virtual_alarm_network = static_init!(
+        capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52833::rtc::Rtc>,
+        capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
+    );
 
     let network = static_init!(
-        drivers::network::Network<'static>,
-        drivers::network::Network::new(network_grant, network_uart, network_buffer)
+        drivers::network::Network<
+            'static,
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52833::rtc::Rtc<'static>>,
+        >,
+        drivers::network::Network::new(
+            network_transport,
+            virtual_alarm_network,
+            network_buffer,
+            network_rx_buffer
+        )
+    );
+
+    network_transport.set_client(network);
+    virtual_alarm_network.set_alarm_client(network);
+
+    // The processes and the kernel capsules share the network through the HTTP mux
+    let mux_http = static_init!(
+        drivers::virtual_http::MuxHttp<'static>,
+        drivers::virtual_http::MuxHttp::new(network)
+    );
+    network.set_client(mux_http);
+
+    let network_http = static_init!(
+        drivers::virtual_http::VirtualHttpDevice<'static>,
+        drivers::virtual_http::VirtualHttpDevice::new(mux_http)
+    );
+    network_http.setup();
+
+    let network_driver = static_init!(
+        drivers::network_driver::NetworkDriver<'static>,
+        drivers::network_driver::NetworkDriver::new(network_grant, network_http)
+    );
+    network_http.set_client(network_driver);
+    network.set_push_client(network_driver);
+
+    // The proxy knows the board by the device ID in the FICR (DEVICEID[0] and DEVICEID[1])
+    let device_id = core::ptr::read_volatile(0x1000_0060 as *const u32) as u64
+        | (core::ptr::read_volatile(0x1000_0064 as *const u32) as u64) << 32;
+    network.set_device_id(device_id);
+    network.set_link_client(network_driver);
+    network_driver.set_proxy_link(network);
+    // Requests fail until the proxy answers; the handshake is sent again while it does not
+    let _ = network.handshake();
+
+    // Offline queue: the POST requests that cannot reach the server are kept in the last
+    // 32K of the flash (see layout.ld), in a TicKV store
+    let sip_hash = static_init!(
+        capsules::sip_hash::SipHasher24,
+        capsules::sip_hash::SipHasher24::new(dynamic_deferred_caller)
     );
+    sip_hash.initialize_callback_handle(dynamic_deferred_caller.register(sip_hash).unwrap());
 
-    network_uart.set_transmit_client(network);
-    network_uart.set_receive_client(network);
+    let tickv_read_buffer = static_init!([u8; 4096], [0; 4096]);
+    let tickv_page_buffer = static_init!(
+        nrf52833::nvmc::NrfPage,
+        nrf52833::nvmc::NrfPage::default()
+    );
+    let tickv = components::tickv::TicKVComponent::new(
+        sip_hash,
+        mux_flash,
+        0x78000 / 4096,
+        0x8000,
+        tickv_read_buffer,
+        tickv_page_buffer,
+    )
+    .finalize(components::tickv_component_helper!(
+        nrf52833::nvmc::Nvmc,
+        capsules::sip_hash::SipHasher24
+    ));
+    sip_hash.set_client(tickv);
+
+    let offline_http = static_init!(
+        drivers::virtual_http::VirtualHttpDevice<'static>,
+        drivers::virtual_http::VirtualHttpDevice::new(mux_http)
+    );
+    offline_http.setup();
+
+    let offline_key = static_init!([u8; 8], [0; 8]);
+    let offline_unhashed_key = static_init!([u8; 8], [0; 8]);
+    let offline_record_buffer = static_init!([u8; 512], [0; 512]);
+    let offline_store_buffer = static_init!(
+        [u8; drivers::offline_queue::STORE_BUFFER_LEN],
+        [0; drivers::offline_queue::STORE_BUFFER_LEN]
+    );
+    let offline_replay_buffer = static_init!([u8; 512], [0; 512]);
+
+    let offline_queue = static_init!(
+        drivers::offline_queue::PersistentQueue<
+            'static,
+            capsules::tickv::TicKVStore<
+                'static,
+                capsules::virtual_flash::FlashUser<'static, nrf52833::nvmc::Nvmc>,
+                capsules::sip_hash::SipHasher24<'static>,
+            >,
+        >,
+        drivers::offline_queue::PersistentQueue::new(
+            tickv,
+            offline_http,
+            offline_key,
+            offline_unhashed_key,
+            offline_record_buffer,
+            offline_store_buffer,
+            offline_replay_buffer
+        )
+    );
+    tickv.set_client(offline_queue);
+    offline_http.set_client(offline_queue);
+    offline_queue.set_client(network_driver);
+    network_driver.set_offline_queue(offline_queue);
+    offline_queue.load();
+
+    // API credential: added by the network to the requests to its origin, kept in the flash
+    // page before the offline queue (see layout.ld) and provisioned by the process named
+    // PROVISIONING_PROCESS only, in provisioning mode
+    let credential_flash = components::flash::FlashUserComponent::new(mux_flash).finalize(
+        components::flash_user_component_helper!(nrf52833::nvmc::Nvmc),
+    );
+    let credential_page_buffer = static_init!(
+        nrf52833::nvmc::NrfPage,
+        nrf52833::nvmc::NrfPage::default()
+    );
+    let credential = static_init!(
+        drivers::credential::FlashCredential<
+            'static,
+            capsules::virtual_flash::FlashUser<'static, nrf52833::nvmc::Nvmc>,
+        >,
+        drivers::credential::FlashCredential::new(
+            credential_flash,
+            0x77000 / 4096,
+            credential_page_buffer
+        )
+    );
+    credential_flash.set_client(credential);
+    network.set_credential(credential);
+    network_driver.set_credential_store(credential);
+    credential.set_client(network_driver);
+    credential.load();
 
     //--------------------------------------------------------------------------
     // FINAL SETUP AND BOARD BOOT
@@ -642,7 +862,8 @@ pub unsafe fn main() {
         ninedof,
         buzzer,
         sound_pressure,
-        network,
+        network: network_driver,
+        provisioning_filter: drivers::credential::ProvisioningFilter::new(PROVISIONING_PROCESS, provisioning),
         adc: adc_syscall,
         alarm,
         app_flash,
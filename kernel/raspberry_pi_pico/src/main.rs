@@ -17,11 +17,17 @@ use kernel::component::Component;
 use kernel::debug;
 use kernel::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::hil::led::LedHigh;
+use kernel::hil::time::Alarm;
+use kernel::hil::uart::{Receive, Transmit};
 use kernel::platform::{KernelResources, SyscallDriverLookup};
 use kernel::scheduler::round_robin::RoundRobinSched;
 use kernel::syscall::SyscallDriver;
 use kernel::{capabilities, create_capability, static_init, Kernel};
 
+use drivers::http::Http;
+use drivers::network::ProxyLink;
+use drivers::transport::Transport;
+
 use rp2040;
 use rp2040::adc::{Adc, Channel};
 use rp2040::chip::{Rp2040, Rp2040DefaultPeripherals};
@@ -74,6 +80,7 @@ pub struct RaspberryPiPico {
     led: &'static capsules::led::LedDriver<'static, LedHigh<'static, RPGpioPin<'static>>>,
     adc: &'static capsules::adc::AdcVirtualized<'static>,
     temperature: &'static capsules::temperature::TemperatureSensor<'static>,
+    network: &'static drivers::network_driver::NetworkDriver<'static>,
 
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm0p::systick::SysTick,
@@ -92,6 +99,7 @@ impl SyscallDriverLookup for RaspberryPiPico {
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             capsules::adc::DRIVER_NUM => f(Some(self.adc)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temperature)),
+            drivers::network_driver::DRIVER_NUM => f(Some(self.network)),
             _ => f(None),
         }
     }
@@ -295,7 +303,7 @@ pub unsafe fn main() {
     let memory_allocation_capability = create_capability!(capabilities::MemoryAllocationCapability);
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+        static_init!([DynamicDeferredCallClientState; 4], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -312,10 +320,43 @@ pub unsafe fn main() {
     )
     .finalize(components::alarm_component_helper!(RPTimer));
 
-    // UART
-    // Create a shared UART channel for kernel debug.
+    // Split the peripherals.uart0 into channels, so that the console, the kernel debug output and the
+    // network traffic cannot interleave (see `drivers::serial_channels`).
+    let serial_channels_tx_buffer = static_init!([u8; 256], [0; 256]);
+    let serial_channels_rx_buffer = static_init!([u8; 256], [0; 256]);
+    let serial_channels = static_init!(
+        drivers::serial_channels::SerialChannels<'static>,
+        drivers::serial_channels::SerialChannels::new(
+            &peripherals.uart0,
+            serial_channels_tx_buffer,
+            serial_channels_rx_buffer,
+            dynamic_deferred_caller
+        )
+    );
+    serial_channels.initialize_callback_handle(dynamic_deferred_caller.register(serial_channels).unwrap());
+    peripherals.uart0.set_transmit_client(serial_channels);
+    peripherals.uart0.set_receive_client(serial_channels);
+
+    let console_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_CONSOLE
+        )
+    );
+    console_channel.setup();
+    let debug_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_DEBUG
+        )
+    );
+    debug_channel.setup();
+
+    // Create a UART mux for the console on its channel.
     let uart_mux = components::console::UartMuxComponent::new(
-        &peripherals.uart0,
+        console_channel,
         115200,
         dynamic_deferred_caller,
     )
@@ -328,8 +369,23 @@ pub unsafe fn main() {
         uart_mux,
     )
     .finalize(());
-    // Create the debugger object that handles calls to `debug!()`.
-    components::debug_writer::DebugWriterComponent::new(uart_mux).finalize(());
+
+    // Create the debugger object that handles calls to `debug!()`, on its own channel.
+    let debug_uart_mux_buffer = static_init!([u8; 64], [0; 64]);
+    let debug_uart_mux = static_init!(
+        capsules::virtual_uart::MuxUart<'static>,
+        capsules::virtual_uart::MuxUart::new(
+            debug_channel,
+            debug_uart_mux_buffer,
+            115200,
+            dynamic_deferred_caller
+        )
+    );
+    debug_uart_mux.initialize_callback_handle(dynamic_deferred_caller.register(debug_uart_mux).unwrap());
+    debug_uart_mux.initialize();
+    debug_channel.set_transmit_client(debug_uart_mux);
+    debug_channel.set_receive_client(debug_uart_mux);
+    components::debug_writer::DebugWriterComponent::new(debug_uart_mux).finalize(());
 
     let gpio = GpioComponent::new(
         board_kernel,
@@ -430,6 +486,74 @@ pub unsafe fn main() {
             .finalize(());
     let _ = process_console.start();
 
+    // NETWORK
+    let network_grant = board_kernel.create_grant(
+        drivers::network_driver::DRIVER_NUM,
+        &memory_allocation_capability,
+    );
+
+    // Transport to the proxy: the network channel of the UART. A dedicated UART or USB CDC
+    // can be given to `UartTransport` instead.
+    let network_channel = static_init!(
+        drivers::serial_channels::SerialChannel<'static>,
+        drivers::serial_channels::SerialChannel::new(
+            serial_channels,
+            drivers::serial_channels::CHANNEL_NETWORK
+        )
+    );
+    network_channel.setup();
+    let network_transport = static_init!(
+        drivers::transport::UartTransport<'static>,
+        drivers::transport::UartTransport::new(network_channel)
+    );
+    network_channel.set_transmit_client(network_transport);
+    network_channel.set_receive_client(network_transport);
+
+    let network_buffer = static_init!([u8; 1024], [0; 1024]);
+    let network_rx_buffer = static_init!([u8; 1024], [0; 1024]);
+
+    let virtual_alarm_network = static_init!(
+        VirtualMuxAlarm<'static, RPTimer>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+
+    let network = static_init!(
+        drivers::network::Network<'static, VirtualMuxAlarm<'static, RPTimer<'static>>>,
+        drivers::network::Network::new(
+            network_transport,
+            virtual_alarm_network,
+            network_buffer,
+            network_rx_buffer
+        )
+    );
+
+    network_transport.set_client(network);
+    virtual_alarm_network.set_alarm_client(network);
+
+    // The processes and the kernel capsules share the network through the HTTP mux
+    let mux_http = static_init!(
+        drivers::virtual_http::MuxHttp<'static>,
+        drivers::virtual_http::MuxHttp::new(network)
+    );
+    network.set_client(mux_http);
+
+    let network_http = static_init!(
+        drivers::virtual_http::VirtualHttpDevice<'static>,
+        drivers::virtual_http::VirtualHttpDevice::new(mux_http)
+    );
+    network_http.setup();
+
+    let network_driver = static_init!(
+        drivers::network_driver::NetworkDriver<'static>,
+        drivers::network_driver::NetworkDriver::new(network_grant, network_http)
+    );
+    network_http.set_client(network_driver);
+    network.set_push_client(network_driver);
+    network.set_link_client(network_driver);
+    network_driver.set_proxy_link(network);
+    // Requests fail until the proxy answers; the handshake is sent again while it does not
+    let _ = network.handshake();
+
     let scheduler = components::sched::round_robin::RoundRobinComponent::new(&PROCESSES)
         .finalize(components::rr_component_helper!(NUM_PROCS));
 
@@ -445,6 +569,7 @@ pub unsafe fn main() {
         console,
         adc: adc_syscall,
         temperature: temp,
+        network: network_driver,
 
         scheduler,
         systick: cortexm0p::systick::SysTick::new_with_calibration(125_000_000),
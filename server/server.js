@@ -112,6 +112,38 @@ MongoClient.connect(connString, { useUnifiedTopology: true })
         .catch((error) => console.error(error));
     });
 
+    // API (method: GET, format: event stream): each notification once, when it is due
+    app.get("/api/notifications/stream", (req, res) => {
+      res.writeHead(200, {
+        "Content-Type": "text/event-stream",
+        "Cache-Control": "no-cache",
+        Connection: "keep-alive",
+      });
+
+      let lastCheck = (Date.now() / 1000) | 0;
+      const timer = setInterval(() => {
+        const now = (Date.now() / 1000) | 0;
+        db.collection("notifications")
+          .find({
+            $and: [
+              { timestamp: { $gt: lastCheck } },
+              { timestamp: { $lte: now } },
+            ],
+          })
+          .toArray()
+          .then((results) => {
+            results.forEach((notification) => {
+              console.log(notification);
+              res.write("data: " + JSON.stringify(notification) + "\n\n");
+            });
+          })
+          .catch((error) => console.error(error));
+        lastCheck = now;
+      }, 1000);
+
+      req.on("close", () => clearInterval(timer));
+    });
+
     // UI (method: POST)
     app.post("/api/snooze", (req, res) => {
       console.log(req.body);